@@ -40,6 +40,11 @@ pub struct Options {
 }
 
 pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
+    run_bundle_with_source_map(entry, options).map(|(source, _)| source)
+}
+
+/// Bundles the entry like `run_bundle`, also returning the (JSON) source map of the output.
+pub fn run_bundle_with_source_map(entry: &str, options: &Options) -> Result<(String, String)> {
     // Create SWC globals and an LRC sourcemap.
     let globals = Globals::default();
     let cm = Lrc::new(SourceMap::new(FilePathMapping::empty()));
@@ -78,6 +83,7 @@ pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
         .unwrap();
 
     let mut buf = vec![];
+    let mut mappings = vec![];
 
     {
         let mut cfg = swc_ecma_codegen::Config::default();
@@ -87,7 +93,12 @@ pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
             cfg,
             cm: cm.clone(),
             comments: None,
-            wr: Box::new(JsWriter::new(cm, "\n", &mut buf, None)),
+            wr: Box::new(JsWriter::new(
+                cm.clone(),
+                "\n",
+                &mut buf,
+                Some(&mut mappings),
+            )),
         };
 
         emitter.emit_module(&bundle.module)?;
@@ -105,9 +116,15 @@ pub fn run_bundle(entry: &str, options: &Options) -> Result<String> {
         messages.iter().rev().for_each(|msg| {
             source.insert_str(0, msg);
         });
+        // Keep the source map in line with the code below the messages.
+        let lines: usize = messages.iter().map(|msg| msg.matches('\n').count()).sum();
+        mappings
+            .iter_mut()
+            .for_each(|(_, pos)| pos.line += lines as u32);
     }
 
-    Ok(source)
+    let source_map = transpilers::source_map_to_string(cm, &mappings);
+    Ok((source, source_map))
 }
 
 struct Loader<'s> {
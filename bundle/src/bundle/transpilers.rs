@@ -243,7 +243,7 @@ impl Wasm {
 }
 
 /// Returns the string (JSON) representation of the source-map.
-fn source_map_to_string(cm: Lrc<SourceMap>, mappings: &[(BytePos, LineCol)]) -> String {
+pub(crate) fn source_map_to_string(cm: Lrc<SourceMap>, mappings: &[(BytePos, LineCol)]) -> String {
     let mut buffer = Vec::new();
     let source_map = cm.build_source_map(mappings);
     source_map.to_writer(&mut buffer).unwrap();
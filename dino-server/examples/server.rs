@@ -1,5 +1,7 @@
 use anyhow::Result;
-use dino_server::{ProjectConfig, SwappableAppRouter, TenentRouter, start_server};
+use dino_server::{
+    ListenAddr, ProjectConfig, ServerConfig, SwappableAppRouter, TenentRouter, start_server,
+};
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{
     Layer as _, fmt::Layer, layer::SubscriberExt, util::SubscriberInitExt as _,
@@ -30,10 +32,14 @@ async fn main() -> Result<()> {
         return{hello:hello};
     })();
     "#;
-    let router = SwappableAppRouter::try_new(code, config.routes)?;
+    let router = SwappableAppRouter::try_new(code, config)?;
 
+    let config = ServerConfig {
+        listen: vec![ListenAddr::Tcp(([127, 0, 0, 1], 8888).into())],
+        ..Default::default()
+    };
     start_server(
-        8888,
+        config,
         vec![TenentRouter::new("localhost".to_string(), router)],
     )
     .await?;
@@ -0,0 +1,442 @@
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, TrySendError},
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use axum::{
+    body::HttpBody,
+    extract::Request,
+    http::{
+        HeaderMap, Method, Uri, Version,
+        header::{REFERER, USER_AGENT},
+    },
+    response::Response,
+};
+use serde_json::json;
+use tracing::warn;
+
+use crate::{
+    AccessLogConfig, AccessLogFormat, LogDestination, listener::Peer, metrics::RequestLabels,
+    path_and_query, utils::random_u64,
+};
+
+// set on every response, taken from the request if a proxy in front set it
+pub const X_REQUEST_ID: &str = "x-request-id";
+const MAX_REQUEST_ID_LEN: usize = 128;
+const REDACTED: &str = "[redacted]";
+// lines waiting for the writer, more are dropped rather than holding up the requests
+const QUEUE_SIZE: usize = 8192;
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    // lowercase header names
+    redact: HashSet<String>,
+    redact_query: HashSet<String>,
+    // to the thread writing the lines, taken on drop so it writes the queued ones and stops
+    lines: Option<mpsc::SyncSender<String>>,
+    writer: Option<JoinHandle<()>>,
+    // since the last line queued
+    dropped: AtomicU64,
+}
+
+// taken before the request is served, it's consumed by then
+pub struct RequestInfo {
+    time: SystemTime,
+    peer: Peer,
+    host: String,
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    request_id: String,
+}
+
+impl AccessLog {
+    pub fn try_new(config: &AccessLogConfig) -> Result<Self> {
+        let out: Box<dyn Write + Send> = match &config.destination {
+            LogDestination::Stdout => Box::new(io::stdout()),
+            LogDestination::Stderr => Box::new(io::stderr()),
+            LogDestination::File(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open {}", path.display()))?,
+            ),
+        };
+        Ok(Self::new(config, out))
+    }
+
+    fn new(config: &AccessLogConfig, out: Box<dyn Write + Send>) -> Self {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        Self {
+            format: config.format,
+            redact: config.redact.iter().map(|h| h.to_lowercase()).collect(),
+            redact_query: config.redact_query.iter().cloned().collect(),
+            lines: Some(tx),
+            writer: Some(thread::spawn(move || write_lines(out, rx))),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn log(
+        &self,
+        req: &RequestInfo,
+        res: &Response,
+        labels: &RequestLabels,
+        elapsed: Duration,
+    ) {
+        let mut line = self.format(req, res, labels, elapsed);
+        line.push('\n');
+        let Some(lines) = &self.lines else {
+            return;
+        };
+        match lines.try_send(line) {
+            Ok(()) => {
+                let dropped = self.dropped.swap(0, Ordering::Relaxed);
+                if dropped > 0 {
+                    warn!("dropped {} lines of the access log, it's behind", dropped);
+                }
+            }
+            Err(TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+
+    fn format(
+        &self,
+        req: &RequestInfo,
+        res: &Response,
+        labels: &RequestLabels,
+        elapsed: Duration,
+    ) -> String {
+        let size = res.body().size_hint().exact();
+        let url = self.url(&req.uri);
+        match self.format {
+            AccessLogFormat::Common | AccessLogFormat::Combined => {
+                // %h - - [%t] "%r" %>s %b
+                let mut line = format!(
+                    "{} - - [{}] \"{} {} {:?}\" {} {}",
+                    req.peer,
+                    clf_time(req.time),
+                    req.method,
+                    url,
+                    req.version,
+                    res.status().as_u16(),
+                    size.map(|s| s.to_string())
+                        .unwrap_or_else(|| "-".to_string())
+                );
+                if self.format == AccessLogFormat::Combined {
+                    let header = |name: &str| {
+                        self.header(&req.headers, name)
+                            .unwrap_or_else(|| "-".to_string())
+                    };
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        escape(&header(REFERER.as_str())),
+                        escape(&header(USER_AGENT.as_str()))
+                    ));
+                }
+                line
+            }
+            AccessLogFormat::Json => {
+                let headers: serde_json::Map<_, _> = req
+                    .headers
+                    .keys()
+                    .filter_map(|name| {
+                        let value = self.header(&req.headers, name.as_str())?;
+                        Some((name.to_string(), value.into()))
+                    })
+                    .collect();
+                let line = json!({
+                    "time": rfc3339(req.time),
+                    "request_id": req.request_id,
+                    "remote_addr": req.peer.to_string(),
+                    "host": req.host,
+                    "method": req.method.as_str(),
+                    "url": url,
+                    "version": format!("{:?}", req.version),
+                    "status": res.status().as_u16(),
+                    "size": size,
+                    "duration_ms": elapsed.as_secs_f64() * 1000.0,
+                    "tenant": labels.tenant,
+                    "route": labels.route,
+                    "handler": labels.handler,
+                    "headers": headers,
+                });
+                line.to_string()
+            }
+        }
+    }
+
+    // the path and query, with the values of the redacted params replaced
+    fn url(&self, uri: &Uri) -> String {
+        let Some(query) = uri.query() else {
+            return path_and_query(uri);
+        };
+        let params: Vec<_> = query
+            .split('&')
+            .map(|param| match param.split_once('=') {
+                Some((name, _)) if self.redact_query.contains(name) => {
+                    format!("{}={}", name, REDACTED)
+                }
+                _ => param.to_string(),
+            })
+            .collect();
+        format!("{}?{}", uri.path(), params.join("&"))
+    }
+
+    // the values of a repeated header are joined
+    fn header(&self, headers: &HeaderMap, name: &str) -> Option<String> {
+        let values: Vec<_> = headers
+            .get_all(name)
+            .iter()
+            .map(|v| String::from_utf8_lossy(v.as_bytes()).to_string())
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        match self.redact.contains(name) {
+            true => Some(REDACTED.to_string()),
+            false => Some(values.join(", ")),
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        drop(self.lines.take());
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+// the lines queued meanwhile are flushed together, a line is written at once so they never
+// interleave
+fn write_lines(out: Box<dyn Write + Send>, lines: mpsc::Receiver<String>) {
+    let mut out = BufWriter::new(out);
+    while let Ok(line) = lines.recv() {
+        let mut ret = out.write_all(line.as_bytes());
+        while let Ok(line) = lines.try_recv() {
+            ret = ret.and_then(|_| out.write_all(line.as_bytes()));
+        }
+        if let Err(e) = ret.and_then(|_| out.flush()) {
+            warn!("failed to write the access log: {}", e);
+        }
+    }
+}
+
+impl RequestInfo {
+    pub fn new(req: &Request, peer: Peer, host: &str, request_id: &str) -> Self {
+        Self {
+            time: SystemTime::now(),
+            peer,
+            host: host.to_string(),
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            headers: req.headers().clone(),
+            request_id: request_id.to_string(),
+        }
+    }
+}
+
+// keep a sane id from the client or a proxy in front, otherwise make one up
+pub fn request_id(headers: &HeaderMap) -> String {
+    let id = headers
+        .get(X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.bytes().all(|b| b.is_ascii_graphic())
+        });
+    match id {
+        Some(id) => id.to_string(),
+        None => format!("{:016x}{:016x}", random_u64(), random_u64()),
+    }
+}
+
+// a quoted field must not end the quotes or the line
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// 10/Oct/2000:13:55:36 +0000
+fn clf_time(time: SystemTime) -> String {
+    let (year, month, day, secs) = utc(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+// 2000-10-10T13:55:36.123Z
+fn rfc3339(time: SystemTime) -> String {
+    let (year, month, day, secs) = utc(time);
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_millis())
+        .unwrap_or_default();
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        millis
+    )
+}
+
+// (year, month, day, seconds of the day) of a time after the epoch
+fn utc(time: SystemTime) -> (i64, u32, u32, u64) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    // days to the civil date, http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = (secs / 86400) as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day, secs % 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use axum::body::Body;
+
+    use super::*;
+
+    // shared with the log, to read what it wrote
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn access_log(format: AccessLogFormat) -> (AccessLog, Buffer) {
+        let config = AccessLogConfig {
+            format,
+            ..Default::default()
+        };
+        let buffer = Buffer::default();
+        (AccessLog::new(&config, Box::new(buffer.clone())), buffer)
+    }
+
+    fn request() -> RequestInfo {
+        let req = Request::get("/api/1?a=b")
+            .header("authorization", "Bearer secret")
+            .header("user-agent", "curl/8.0 \"x\"")
+            .body(Body::empty())
+            .unwrap();
+        let mut info = RequestInfo::new(
+            &req,
+            Peer::Tcp(([10, 0, 0, 1], 4000).into()),
+            "a.com",
+            "abc",
+        );
+        // 2000-10-10T13:55:36.123Z
+        info.time = UNIX_EPOCH + Duration::from_millis(971_186_136_123);
+        info
+    }
+
+    #[test]
+    fn access_log_should_write_apache_formats() {
+        let res = Response::new(Body::from("hello"));
+        let labels = RequestLabels::default();
+
+        let (log, buffer) = access_log(AccessLogFormat::Common);
+        log.log(&request(), &res, &labels, Duration::ZERO);
+        // the queued lines are written before it's gone
+        drop(log);
+        let common = r#"10.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /api/1?a=b HTTP/1.1" 200 5"#;
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            format!("{}\n", common)
+        );
+
+        let (log, _) = access_log(AccessLogFormat::Combined);
+        let line = log.format(&request(), &res, &labels, Duration::ZERO);
+        assert_eq!(line, format!(r#"{} "-" "curl/8.0 \"x\"""#, common));
+    }
+
+    #[test]
+    fn access_log_should_redact_headers_and_query() -> Result<()> {
+        let res = Response::new(Body::empty());
+        let labels = RequestLabels {
+            tenant: "app".to_string(),
+            route: "/api/{id}".to_string(),
+            handler: "hello".to_string(),
+            method: "GET".to_string(),
+        };
+        let (log, _) = access_log(AccessLogFormat::Json);
+        let line = log.format(&request(), &res, &labels, Duration::from_millis(5));
+        let value: serde_json::Value = serde_json::from_str(&line)?;
+        assert_eq!(value["time"], "2000-10-10T13:55:36.123Z");
+        assert_eq!(value["request_id"], "abc");
+        assert_eq!(value["route"], "/api/{id}");
+        assert_eq!(value["size"], 0);
+        assert_eq!(value["duration_ms"], 5.0);
+        assert_eq!(value["headers"]["authorization"], REDACTED);
+        assert_eq!(value["headers"]["user-agent"], "curl/8.0 \"x\"");
+
+        let uri = "/a?token=1&b=2&token&password=".parse()?;
+        assert_eq!(
+            log.url(&uri),
+            "/a?token=[redacted]&b=2&token&password=[redacted]"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn request_id_should_be_kept_or_generated() {
+        let mut headers = HeaderMap::new();
+        let id = request_id(&headers);
+        assert_eq!(id.len(), 32);
+        assert_ne!(id, request_id(&headers));
+
+        headers.insert(X_REQUEST_ID, "req-1".parse().unwrap());
+        assert_eq!(request_id(&headers), "req-1");
+        headers.insert(X_REQUEST_ID, "a b".parse().unwrap());
+        assert_eq!(request_id(&headers).len(), 32);
+    }
+}
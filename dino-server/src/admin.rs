@@ -0,0 +1,1053 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Result, anyhow};
+use arc_swap::ArcSwap;
+use axum::{
+    Json, Router,
+    extract::{DefaultBodyLimit, Path, Request, State},
+    http::{StatusCode, header::CONTENT_TYPE},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+};
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    ArtifactFiles, ArtifactManifest, AuthConfig, BuildMetadata, ProjectConfig, Split, SplitRule,
+    SwappableAppRouter, Tenants, artifact::read_config_file, error::AppError, metrics::Metrics,
+    utils::is_valid_label,
+};
+
+// bundles are way bigger than the usual json body
+const MAX_BUNDLE_SIZE: usize = 32 * 1024 * 1024;
+// for evaluating an uploaded bundle, its top level code may never return
+const VALIDATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// the projects served by the server, the tenants are rebuilt from them on every change
+pub(crate) struct Registry {
+    projects: Mutex<IndexMap<String, Project>>,
+    tenants: Arc<ArcSwap<Tenants>>,
+    aliases: HashMap<String, String>,
+    default_host: Option<String>,
+    previews: Option<Previews>,
+    // deployments kept per project, the oldest ones except the active one are dropped first
+    retention: usize,
+    validate_timeout: Duration,
+}
+
+// a project the server was started with
+pub(crate) struct InitialProject {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub router: SwappableAppRouter,
+    pub split: Option<Arc<Split>>,
+}
+
+// every deployment is served at `<scheme>://<project>-<hash>.<domain>[:<port>]`
+#[derive(Debug, Clone)]
+pub(crate) struct Previews {
+    pub domain: String,
+    pub scheme: &'static str,
+    pub port: Option<u16>,
+}
+
+#[derive(Clone)]
+struct Project {
+    // the production hosts
+    hosts: Vec<String>,
+    // serves the production hosts, swapped on promotion so requests in flight are kept
+    router: SwappableAppRouter,
+    // oldest first
+    deployments: Vec<Deployment>,
+    // the deployment served on the production hosts
+    active: Option<String>,
+    // the deployments taking a share of the production traffic from the active one
+    split: Vec<SplitTarget>,
+}
+
+#[derive(Clone)]
+struct Deployment {
+    hash: String,
+    // of the code and config, a hash can't be redeployed with a different content
+    digest: String,
+    // unix timestamp in seconds
+    created_at: u64,
+    metadata: Option<BuildMetadata>,
+    // serves the preview host, never swapped
+    router: SwappableAppRouter,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeployRequest {
+    // of the artifact the code and config come from, their digests are checked against it.
+    // its hash names the deployment, derived from the code and config without one
+    #[serde(default)]
+    pub manifest: Option<ArtifactManifest>,
+    // the production hosts, only used by a production deployment which keeps the current
+    // ones if empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    // the bundled js code
+    pub code: String,
+    // the config.yml of the project
+    pub config: String,
+    // the other files of the artifact, e.g. the source map and the assets, base64 encoded
+    #[serde(default, with = "base64_files")]
+    pub files: ArtifactFiles,
+    // serve the deployment on the production hosts, otherwise it's a preview only
+    #[serde(default)]
+    pub prod: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PromoteRequest {
+    pub hash: String,
+    // the production hosts, keeps the current ones if empty
+    #[serde(default)]
+    pub hosts: Vec<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RollbackRequest {
+    // the deployment before the active one if not set
+    #[serde(default)]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct SplitRequest {
+    // replaces the current split, the active deployment serves the rest
+    #[serde(default)]
+    pub deployments: Vec<SplitTarget>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitTarget {
+    pub hash: String,
+    #[serde(flatten)]
+    pub rule: SplitRule,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ProjectInfo {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub active: Option<String>,
+    #[serde(default)]
+    pub split: Vec<SplitTarget>,
+    // oldest first, a new deployment is the last one
+    pub deployments: Vec<DeploymentInfo>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeploymentInfo {
+    pub hash: String,
+    pub created_at: u64,
+    pub metadata: Option<BuildMetadata>,
+    pub preview_url: Option<String>,
+}
+
+#[derive(Clone)]
+struct AdminState {
+    registry: Arc<Registry>,
+    auth: Arc<AuthConfig>,
+    metrics: Arc<Metrics>,
+}
+
+impl Registry {
+    pub fn try_new(
+        projects: Vec<InitialProject>,
+        tenants: Arc<ArcSwap<Tenants>>,
+        aliases: HashMap<String, String>,
+        default_host: Option<String>,
+        previews: Option<Previews>,
+        retention: usize,
+    ) -> Result<Self> {
+        let mut registry = IndexMap::new();
+        for InitialProject {
+            name,
+            hosts,
+            router,
+            split,
+        } in projects
+        {
+            let current = router.load();
+            // the config file is gone, the code alone identifies the initial deployment unless
+            // it's named by the split
+            let hash = split.as_ref().map(|s| s.primary().to_string());
+            let deployment = Deployment::try_new(hash, &current.code, "", current.config.clone())?;
+            let mut project = Project {
+                hosts,
+                router,
+                active: Some(deployment.hash.clone()),
+                deployments: vec![deployment],
+                split: vec![],
+            };
+            for (hash, router, rule) in split.iter().flat_map(|s| s.canaries()) {
+                let code = router.load().code.clone();
+                let deployment = Deployment::new(Some(hash.to_string()), &code, "", router.clone());
+                project.deployments.push(deployment);
+                project.split.push(SplitTarget {
+                    hash: hash.to_string(),
+                    rule: rule.clone(),
+                });
+            }
+            registry.insert(name, project);
+        }
+        let registry = Self {
+            projects: Mutex::new(registry),
+            tenants,
+            aliases,
+            default_host,
+            previews,
+            retention: retention.max(1),
+            validate_timeout: VALIDATE_TIMEOUT,
+        };
+        // serve the previews of the initial deployments as well
+        let tenants = registry.build_tenants(registry.projects.lock().unwrap().iter())?;
+        registry.tenants.store(Arc::new(tenants));
+        Ok(registry)
+    }
+
+    pub fn list(&self) -> Vec<ProjectInfo> {
+        let projects = self.projects.lock().unwrap();
+        projects
+            .iter()
+            .map(|(name, project)| self.info(name, project))
+            .collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<ProjectInfo, AppError> {
+        let projects = self.projects.lock().unwrap();
+        let project = projects
+            .get(name)
+            .ok_or_else(|| AppError::ProjectNotFound(name.to_string()))?;
+        Ok(self.info(name, project))
+    }
+
+    // add a deployment to the project, created if needed, and promote it with `prod`
+    pub fn deploy(&self, name: &str, req: DeployRequest) -> Result<ProjectInfo, AppError> {
+        if !is_valid_label(name) {
+            return Err(AppError::BadRequest(format!(
+                "invalid project name {}, expect lowercase letters, digits and `-`",
+                name
+            )));
+        }
+        if let Some(manifest) = &req.manifest {
+            if !is_valid_label(&manifest.hash) {
+                return Err(AppError::BadRequest(format!(
+                    "invalid hash {}",
+                    manifest.hash
+                )));
+            }
+            manifest
+                .verify(&req.code, &req.config, &req.files)
+                .map_err(|e| AppError::BadRequest(format!("{:#}", e)))?;
+        }
+        let mut config: ProjectConfig = serde_yaml::from_str(&req.config)
+            .map_err(|e| AppError::BadRequest(format!("invalid config: {}", e)))?;
+        config
+            .load_files(|path| read_config_file(&req.files, path))
+            .map_err(|e| AppError::BadRequest(format!("{:#}", e)))?;
+        let deadline = Instant::now() + self.validate_timeout;
+        config
+            .validate_handlers_until(&req.code, deadline)
+            .map_err(|e| match Instant::now() >= deadline {
+                true => AppError::BadRequest(format!(
+                    "evaluating the code took longer than {:?}",
+                    self.validate_timeout
+                )),
+                false => AppError::BadRequest(format!("{:#}", e)),
+            })?;
+        let (hash, metadata) = match req.manifest {
+            Some(manifest) => (Some(manifest.hash), Some(manifest.metadata)),
+            None => (None, None),
+        };
+        let mut deployment = Deployment::try_new(hash, &req.code, &req.config, config)?;
+        deployment.metadata = metadata;
+        let hash = deployment.hash.clone();
+
+        let mut projects = self.projects.lock().unwrap();
+        let mut project = match projects.get(name) {
+            Some(project) => project.clone(),
+            None => Project::try_new(&deployment)?,
+        };
+        match project.deployments.iter().find(|d| d.hash == hash) {
+            // deploying the same build again is a no-op, its preview keeps serving
+            Some(existing) if existing.digest == deployment.digest => {}
+            Some(_) => {
+                return Err(AppError::Conflict(format!(
+                    "deployment {} of {} exists with a different content",
+                    hash, name
+                )));
+            }
+            None => project.push(deployment, self.retention, req.prod)?,
+        }
+        if req.prod && !req.hosts.is_empty() {
+            project.hosts = req.hosts;
+        }
+        let promote = req.prod.then_some(hash.as_str());
+        let info = self.commit(&mut projects, name, project, promote)?;
+        match req.prod {
+            true => info!("Deployed {} ({}) on {}", name, hash, info.hosts.join(", ")),
+            false => info!("Deployed {} ({}) as a preview", name, hash),
+        }
+        Ok(info)
+    }
+
+    // serve a retained deployment on the production hosts
+    pub fn promote(&self, name: &str, req: PromoteRequest) -> Result<ProjectInfo, AppError> {
+        let mut projects = self.projects.lock().unwrap();
+        let mut project = projects
+            .get(name)
+            .cloned()
+            .ok_or_else(|| AppError::ProjectNotFound(name.to_string()))?;
+        if !req.hosts.is_empty() {
+            project.hosts = req.hosts;
+        }
+        let info = self.commit(&mut projects, name, project, Some(&req.hash))?;
+        info!(
+            "Promoted {} ({}) on {}",
+            name,
+            req.hash,
+            info.hosts.join(", ")
+        );
+        Ok(info)
+    }
+
+    // serve a share of the production traffic by other deployments, e.g. a canary
+    pub fn split(&self, name: &str, req: SplitRequest) -> Result<ProjectInfo, AppError> {
+        let mut projects = self.projects.lock().unwrap();
+        let mut project = projects
+            .get(name)
+            .cloned()
+            .ok_or_else(|| AppError::ProjectNotFound(name.to_string()))?;
+        let Some(active) = project.active.clone() else {
+            return Err(AppError::Conflict(format!(
+                "{} has no production deployment to split",
+                name
+            )));
+        };
+        for target in &req.deployments {
+            if target.hash == active {
+                return Err(AppError::BadRequest(format!(
+                    "{} is the production deployment already",
+                    active
+                )));
+            }
+            if !project.deployments.iter().any(|d| d.hash == target.hash) {
+                return Err(AppError::Conflict(format!("no deployment {}", target.hash)));
+            }
+        }
+        project.split = req.deployments;
+        project
+            .traffic_split()
+            .map_err(|e| AppError::BadRequest(format!("{:#}", e)))?;
+        let info = self.commit(&mut projects, name, project, None)?;
+        let shares: Vec<_> = info
+            .split
+            .iter()
+            .map(|t| format!("{} ({}%)", t.hash, t.rule.weight))
+            .collect();
+        match shares.is_empty() {
+            true => info!("Serving {} by {} only", name, active),
+            false => info!("Split {} from {} to {}", name, active, shares.join(", ")),
+        }
+        Ok(info)
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), AppError> {
+        let mut projects = self.projects.lock().unwrap();
+        if !projects.contains_key(name) {
+            return Err(AppError::ProjectNotFound(name.to_string()));
+        }
+        let others = projects.iter().filter(|(n, _)| *n != name);
+        // fails if an alias or the default host still points to the project
+        let tenants = self.build_tenants(others)?;
+        self.tenants.store(Arc::new(tenants));
+        projects.shift_remove(name);
+        info!("Removed {}", name);
+        Ok(())
+    }
+
+    // serve a previous deployment on the production hosts
+    pub fn rollback(&self, name: &str, req: RollbackRequest) -> Result<ProjectInfo, AppError> {
+        let mut projects = self.projects.lock().unwrap();
+        let project = projects
+            .get(name)
+            .cloned()
+            .ok_or_else(|| AppError::ProjectNotFound(name.to_string()))?;
+        let target = match req.hash {
+            Some(hash) => hash,
+            None => {
+                let active = project
+                    .deployments
+                    .iter()
+                    .position(|d| Some(&d.hash) == project.active.as_ref());
+                active
+                    .and_then(|i| i.checked_sub(1))
+                    .map(|i| project.deployments[i].hash.clone())
+                    .ok_or_else(|| {
+                        AppError::Conflict(format!("no deployment of {} to roll back to", name))
+                    })?
+            }
+        };
+        let info = self.commit(&mut projects, name, project, Some(&target))?;
+        info!("Rolled {} back to {}", name, target);
+        Ok(info)
+    }
+
+    // replace the project and serve `promote` on its production hosts, the hosts are
+    // checked before touching the router so a conflict leaves everything as is
+    fn commit(
+        &self,
+        projects: &mut IndexMap<String, Project>,
+        name: &str,
+        mut project: Project,
+        promote: Option<&str>,
+    ) -> Result<ProjectInfo, AppError> {
+        if promote.is_some() && project.hosts.is_empty() {
+            return Err(AppError::BadRequest(
+                "hosts are required to deploy to production".to_string(),
+            ));
+        }
+        let promoted = match promote {
+            Some(hash) => Some(project.activate(hash)?),
+            None => None,
+        };
+        let name = name.to_string();
+        let others = projects.iter().filter(|(n, _)| **n != name);
+        let tenants = self.build_tenants(others.chain([(&name, &project)]))?;
+        // no js is evaluated under the lock, the deployments were validated when pushed
+        if let Some(router) = promoted {
+            project.router.swap_to(&router.load())?;
+        }
+        self.tenants.store(Arc::new(tenants));
+        let info = self.info(&name, &project);
+        projects.insert(name, project);
+        Ok(info)
+    }
+
+    fn build_tenants<'a>(
+        &self,
+        projects: impl Iterator<Item = (&'a String, &'a Project)>,
+    ) -> Result<Tenants, AppError> {
+        let mut routers = vec![];
+        let mut splits = vec![];
+        for (name, project) in projects {
+            let split = project
+                .traffic_split()
+                .map_err(|e| AppError::Conflict(e.to_string()))?;
+            for host in &project.hosts {
+                routers.push((host.clone(), project.router.clone()));
+                if let Some(split) = &split {
+                    splits.push((host.clone(), split.clone()));
+                }
+            }
+            if let Some(previews) = &self.previews {
+                for deployment in &project.deployments {
+                    let host = previews.host(name, &deployment.hash);
+                    routers.push((host, deployment.router.clone()));
+                }
+            }
+        }
+        Tenants::try_new(routers, self.aliases.clone(), self.default_host.clone())
+            .and_then(|tenants| tenants.with_splits(splits))
+            .map_err(|e| AppError::Conflict(e.to_string()))
+    }
+
+    fn info(&self, name: &str, project: &Project) -> ProjectInfo {
+        let deployments = project
+            .deployments
+            .iter()
+            .map(|d| DeploymentInfo {
+                hash: d.hash.clone(),
+                created_at: d.created_at,
+                metadata: d.metadata.clone(),
+                preview_url: self.previews.as_ref().map(|p| p.url(name, &d.hash)),
+            })
+            .collect();
+        ProjectInfo {
+            name: name.to_string(),
+            hosts: project.hosts.clone(),
+            active: project.active.clone(),
+            split: project.split.clone(),
+            deployments,
+        }
+    }
+}
+
+impl Previews {
+    fn host(&self, name: &str, hash: &str) -> String {
+        format!("{}-{}.{}", name, hash, self.domain)
+    }
+
+    fn url(&self, name: &str, hash: &str) -> String {
+        let host = self.host(name, hash);
+        match self.port {
+            Some(port) => format!("{}://{}:{}", self.scheme, host, port),
+            None => format!("{}://{}", self.scheme, host),
+        }
+    }
+}
+
+impl Project {
+    // the production router starts with the first deployment, it has no hosts yet
+    fn try_new(deployment: &Deployment) -> Result<Self> {
+        let current = deployment.router.load();
+        let router = SwappableAppRouter::try_new(current.code.clone(), current.config.clone())?;
+        Ok(Self {
+            hosts: vec![],
+            router,
+            deployments: vec![],
+            active: None,
+            split: vec![],
+        })
+    }
+
+    // the oldest deployment makes room for the new one, the ones being served on the
+    // production hosts are never dropped. the active one can go if the new one replaces it
+    fn push(
+        &mut self,
+        deployment: Deployment,
+        retention: usize,
+        promote: bool,
+    ) -> Result<(), AppError> {
+        if self.deployments.len() >= retention {
+            let served = |d: &Deployment| {
+                (!promote && Some(&d.hash) == self.active.as_ref())
+                    || self.split.iter().any(|t| t.hash == d.hash)
+            };
+            let Some(oldest) = self.deployments.iter().position(|d| !served(d)) else {
+                return Err(AppError::Conflict(format!(
+                    "the {} retained deployments are all served, none can make room for {}",
+                    retention, deployment.hash
+                )));
+            };
+            self.deployments.remove(oldest);
+        }
+        self.deployments.push(deployment);
+        Ok(())
+    }
+
+    // the router of the deployment, to be swapped into the production one
+    fn activate(&mut self, hash: &str) -> Result<SwappableAppRouter, AppError> {
+        let deployment = self
+            .deployments
+            .iter()
+            .find(|d| d.hash == hash)
+            .ok_or_else(|| AppError::Conflict(format!("no deployment {}", hash)))?;
+        let router = deployment.router.clone();
+        self.active = Some(hash.to_string());
+        // it serves all the traffic the split doesn't take
+        self.split.retain(|t| t.hash != hash);
+        Ok(router)
+    }
+
+    // the active deployment serves what the split deployments don't
+    fn traffic_split(&self) -> Result<Option<Arc<Split>>> {
+        let Some(active) = &self.active else {
+            return Ok(None);
+        };
+        if self.split.is_empty() {
+            return Ok(None);
+        }
+        let mut canaries = vec![];
+        for target in &self.split {
+            let deployment = self
+                .deployments
+                .iter()
+                .find(|d| d.hash == target.hash)
+                .ok_or_else(|| anyhow!("no deployment {}", target.hash))?;
+            let router = deployment.router.clone();
+            canaries.push((target.hash.clone(), router, target.rule.clone()));
+        }
+        Ok(Some(Arc::new(Split::try_new(active.clone(), canaries)?)))
+    }
+}
+
+impl Deployment {
+    fn try_new(
+        hash: Option<String>,
+        code: &str,
+        raw_config: &str,
+        config: ProjectConfig,
+    ) -> Result<Self> {
+        let router = SwappableAppRouter::try_new(code, config)?;
+        Ok(Self::new(hash, code, raw_config, router))
+    }
+
+    // served by the router, e.g. one reloaded on file changes
+    fn new(hash: Option<String>, code: &str, raw_config: &str, router: SwappableAppRouter) -> Self {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(code.as_bytes());
+        hasher.update(raw_config.as_bytes());
+        let digest = hasher.finalize().to_string();
+        let hash = hash.unwrap_or_else(|| digest[..16].to_string());
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            hash,
+            digest,
+            created_at,
+            metadata: None,
+            router,
+        }
+    }
+}
+
+// the file contents may be binary, e.g. images
+mod base64_files {
+    use base64::{Engine as _, engine::general_purpose::STANDARD};
+    use serde::{Deserialize, Deserializer, Serializer, de::Error as _};
+
+    use crate::ArtifactFiles;
+
+    pub fn serialize<S: Serializer>(
+        files: &ArtifactFiles,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(
+            files
+                .iter()
+                .map(|(path, content)| (path, STANDARD.encode(content))),
+        )
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ArtifactFiles, D::Error> {
+        let encoded = std::collections::BTreeMap::<String, String>::deserialize(deserializer)?;
+        encoded
+            .into_iter()
+            .map(|(path, content)| match STANDARD.decode(&content) {
+                Ok(content) => Ok((path, content)),
+                Err(e) => Err(D::Error::custom(format!(
+                    "invalid content of {}: {}",
+                    path, e
+                ))),
+            })
+            .collect()
+    }
+}
+
+// GET    /projects
+// GET    /projects/{name}
+// PUT    /projects/{name}           {"hosts": [...], "code": "...", "config": "...", "files": {}}
+// DELETE /projects/{name}
+// POST   /projects/{name}/promote   {"hash": "...", "hosts": [...]}
+// POST   /projects/{name}/rollback  {"hash": "..."}
+// PUT    /projects/{name}/split     {"deployments": [{"hash": "...", "weight": 10}]}
+// GET    /metrics                   prometheus text format
+pub(crate) fn app(registry: Arc<Registry>, auth: AuthConfig, metrics: Arc<Metrics>) -> Router {
+    let state = AdminState {
+        registry,
+        auth: Arc::new(auth),
+        metrics,
+    };
+    Router::new()
+        .route("/metrics", get(scrape))
+        .route("/projects", get(list))
+        .route("/projects/{name}", get(show).put(deploy).delete(remove))
+        .route("/projects/{name}/promote", post(promote))
+        .route("/projects/{name}/rollback", post(rollback))
+        .route("/projects/{name}/split", put(split))
+        .layer(DefaultBodyLimit::max(MAX_BUNDLE_SIZE))
+        .layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state)
+}
+
+async fn authorize(
+    State(state): State<AdminState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    state.auth.verify(req.headers()).await?;
+    Ok(next.run(req).await)
+}
+
+async fn scrape(State(state): State<AdminState>) -> impl IntoResponse {
+    let text = state.metrics.render(&state.registry.tenants.load());
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], text)
+}
+
+async fn list(State(state): State<AdminState>) -> Json<Vec<ProjectInfo>> {
+    Json(state.registry.list())
+}
+
+async fn show(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+) -> Result<Json<ProjectInfo>, AppError> {
+    Ok(Json(state.registry.get(&name)?))
+}
+
+// compiling the code to validate it blocks, keep it off the async workers
+async fn deploy(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    Json(req): Json<DeployRequest>,
+) -> Result<Json<ProjectInfo>, AppError> {
+    let info = tokio::task::spawn_blocking(move || state.registry.deploy(&name, req))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(Json(info))
+}
+
+async fn remove(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, AppError> {
+    state.registry.remove(&name)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn promote(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    Json(req): Json<PromoteRequest>,
+) -> Result<Json<ProjectInfo>, AppError> {
+    let info = tokio::task::spawn_blocking(move || state.registry.promote(&name, req))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(Json(info))
+}
+
+async fn rollback(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    req: Option<Json<RollbackRequest>>,
+) -> Result<Json<ProjectInfo>, AppError> {
+    let req = req.map(|Json(req)| req).unwrap_or_default();
+    let info = tokio::task::spawn_blocking(move || state.registry.rollback(&name, req))
+        .await
+        .map_err(anyhow::Error::from)??;
+    Ok(Json(info))
+}
+
+async fn split(
+    State(state): State<AdminState>,
+    Path(name): Path<String>,
+    Json(req): Json<SplitRequest>,
+) -> Result<Json<ProjectInfo>, AppError> {
+    Ok(Json(state.registry.split(&name, req)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::header::AUTHORIZATION};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    const CONFIG: &str = "{ name: app, routes: { /: [{ method: GET, handler: hello }] } }";
+
+    fn code(body: &str) -> String {
+        format!(
+            "(function(){{async function hello(){{return{{status:200,headers:{{}},body:'{}'}};}}return{{hello}};}})();",
+            body
+        )
+    }
+
+    fn deploy_req(hosts: &[&str], body: &str, prod: bool) -> DeployRequest {
+        DeployRequest {
+            manifest: None,
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            code: code(body),
+            config: CONFIG.to_string(),
+            files: [("main.mjs.map".to_string(), b"{}".to_vec())].into(),
+            prod,
+        }
+    }
+
+    // as deployed by the cli, with the manifest of the build
+    fn with_hash(mut req: DeployRequest, hash: &str) -> DeployRequest {
+        let metadata = BuildMetadata {
+            dino_version: "0.1.0".to_string(),
+            created_at: 1,
+            entry: "main.ts".to_string(),
+        };
+        let manifest = ArtifactManifest::new(hash, metadata, &req.code, &req.config, &req.files);
+        req.manifest = Some(manifest.unwrap());
+        req
+    }
+
+    fn served(tenants: &ArcSwap<Tenants>, host: &str) -> Option<String> {
+        let tenants = tenants.load();
+        let router = tenants.resolve(host)?.router.load();
+        Some(router.code.clone())
+    }
+
+    fn registry(previews: Option<Previews>) -> Result<(Registry, Arc<ArcSwap<Tenants>>)> {
+        let tenants = Tenants::try_new([], HashMap::new(), None)?;
+        let tenants = Arc::new(ArcSwap::from_pointee(tenants));
+        let registry =
+            Registry::try_new(vec![], tenants.clone(), HashMap::new(), None, previews, 3)?;
+        Ok((registry, tenants))
+    }
+
+    #[test]
+    fn registry_should_deploy_remove_and_rollback() -> Result<()> {
+        let (registry, tenants) = registry(None)?;
+
+        let v1 = registry.deploy("app", deploy_req(&["a.com"], "v1", true))?;
+        assert_eq!(served(&tenants, "a.com"), Some(code("v1")));
+        // a new deployment keeps the router, requests in flight are not dropped
+        let router = tenants.load().resolve("a.com").unwrap().router.clone();
+        let v2 = registry.deploy("app", deploy_req(&[], "v2", true))?;
+        assert_eq!(router.load().code, code("v2"));
+        assert_eq!(v2.hosts, vec!["a.com"]);
+        assert_eq!(v2.deployments.len(), 2);
+        assert_ne!(v1.active, v2.active);
+
+        // hosts of another project conflict
+        let err = registry.deploy("other", deploy_req(&["a.com"], "x", true));
+        assert!(matches!(err, Err(AppError::Conflict(_))));
+        // unknown handlers are rejected
+        let mut req = deploy_req(&["b.com"], "x", true);
+        req.config = req.config.replace("hello", "nope");
+        assert!(matches!(
+            registry.deploy("other", req),
+            Err(AppError::BadRequest(_))
+        ));
+        assert_eq!(served(&tenants, "b.com"), None);
+
+        let info = registry.rollback("app", RollbackRequest::default())?;
+        assert_eq!(info.active, v1.active);
+        assert_eq!(router.load().code, code("v1"));
+        let info = registry.rollback("app", RollbackRequest { hash: v2.active })?;
+        assert_eq!(router.load().code, code("v2"));
+        assert_eq!(registry.list()[0].active, info.active);
+
+        registry.remove("app")?;
+        assert_eq!(served(&tenants, "a.com"), None);
+        assert!(matches!(
+            registry.remove("app"),
+            Err(AppError::ProjectNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn registry_should_keep_the_new_deployment() -> Result<()> {
+        let tenants = Arc::new(ArcSwap::from_pointee(Tenants::try_new(
+            [],
+            HashMap::new(),
+            None,
+        )?));
+        let mut registry =
+            Registry::try_new(vec![], tenants.clone(), HashMap::new(), None, None, 1)?;
+
+        registry.deploy("app", deploy_req(&["a.com"], "v1", true))?;
+        // the active one can't make room for a preview
+        let err = registry.deploy("app", deploy_req(&[], "v2", false));
+        assert!(matches!(err, Err(AppError::Conflict(e)) if e.contains("none can make room")));
+        // but for the deployment replacing it
+        let info = registry.deploy("app", deploy_req(&[], "v2", true))?;
+        assert_eq!(info.deployments.len(), 1);
+        assert_eq!(info.active.as_ref(), Some(&info.deployments[0].hash));
+        assert_eq!(served(&tenants, "a.com"), Some(code("v2")));
+
+        // the evaluation of uploaded code is bounded
+        registry.validate_timeout = Duration::from_millis(100);
+        let mut req = deploy_req(&[], "v3", true);
+        req.code = "(function(){while(true){}})();".to_string();
+        let err = registry.deploy("app", req);
+        assert!(matches!(err, Err(AppError::BadRequest(e)) if e.contains("took longer")));
+        Ok(())
+    }
+
+    #[test]
+    fn registry_should_serve_previews() -> Result<()> {
+        let previews = Previews {
+            domain: "preview.local".to_string(),
+            scheme: "http",
+            port: Some(8888),
+        };
+        let (registry, tenants) = registry(Some(previews))?;
+
+        // a preview isn't served on the production hosts
+        let info = registry.deploy("app", deploy_req(&["a.com"], "v1", false))?;
+        assert_eq!(info.active, None);
+        assert!(info.hosts.is_empty());
+        let v1 = &info.deployments[0];
+        assert_eq!(
+            v1.preview_url,
+            Some(format!("http://app-{}.preview.local:8888", v1.hash))
+        );
+        let v1_host = format!("app-{}.preview.local", v1.hash);
+        assert_eq!(served(&tenants, &v1_host), Some(code("v1")));
+        assert_eq!(served(&tenants, "a.com"), None);
+
+        let info = registry.deploy("app", deploy_req(&["a.com"], "v2", true))?;
+        let v2 = &info.deployments[1];
+        assert_eq!(info.active.as_ref(), Some(&v2.hash));
+        assert_eq!(served(&tenants, "a.com"), Some(code("v2")));
+        // previews are immutable, promoting another deployment doesn't change them
+        let hash = Some(v1.hash.clone());
+        registry.rollback("app", RollbackRequest { hash })?;
+        assert_eq!(served(&tenants, "a.com"), Some(code("v1")));
+        let v2_host = format!("app-{}.preview.local", v2.hash);
+        assert_eq!(served(&tenants, &v2_host), Some(code("v2")));
+
+        let err = registry.deploy("App", deploy_req(&[], "v1", false));
+        assert!(matches!(err, Err(AppError::BadRequest(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn registry_should_keep_retained_deployments_immutable() -> Result<()> {
+        let (registry, tenants) = registry(None)?;
+        let deploy = |hash: &str, body: &str| {
+            registry.deploy("app", with_hash(deploy_req(&[], body, false), hash))
+        };
+
+        deploy("h1", "v1")?;
+        let info = deploy("h1", "v1")?;
+        assert_eq!(info.deployments.len(), 1);
+        assert!(matches!(deploy("h1", "v2"), Err(AppError::Conflict(_))));
+        assert!(matches!(deploy("H1", "v1"), Err(AppError::BadRequest(_))));
+        // the manifest of another build doesn't vouch for the code
+        let mut req = with_hash(deploy_req(&[], "v1", false), "h2");
+        req.code = code("v2");
+        let err = registry.deploy("app", req);
+        assert!(matches!(err, Err(AppError::BadRequest(_))));
+        // nor for a build missing a file of the artifact
+        let mut req = with_hash(deploy_req(&[], "v1", false), "h2");
+        req.files.clear();
+        let err = registry.deploy("app", req);
+        assert!(matches!(err, Err(AppError::BadRequest(e)) if e.contains("main.mjs.map")));
+        // binary files go over json
+        let mut req = with_hash(deploy_req(&[], "v1", false), "h2");
+        req.files.insert("assets/a.bin".to_string(), vec![0, 255]);
+        let req: DeployRequest = serde_json::from_str(&serde_json::to_string(&req)?)?;
+        assert_eq!(req.files["assets/a.bin"], [0, 255]);
+        // the files the config refers to come with it
+        let jwt_req = || {
+            let mut req = deploy_req(&[], "v1", false);
+            req.config = CONFIG.replace("}] } }", "}] }, auth: { jwt: { jwks: jwks.json } } }");
+            req
+        };
+        let err = registry.deploy("jwt", jwt_req());
+        assert!(matches!(err, Err(AppError::BadRequest(e)) if e.contains("jwks.json")));
+        let mut req = jwt_req();
+        req.files
+            .insert("files/jwks.json".to_string(), br#"{"keys": []}"#.to_vec());
+        registry.deploy("jwt", with_hash(req, "h1"))?;
+
+        let promote = |hash: &str, hosts: &[&str]| {
+            let hash = hash.to_string();
+            let hosts = hosts.iter().map(|h| h.to_string()).collect();
+            registry.promote("app", PromoteRequest { hash, hosts })
+        };
+        assert!(matches!(promote("h1", &[]), Err(AppError::BadRequest(_))));
+        assert!(matches!(
+            promote("h9", &["a.com"]),
+            Err(AppError::Conflict(_))
+        ));
+        assert_eq!(served(&tenants, "a.com"), None);
+        promote("h1", &["a.com"])?;
+        assert_eq!(served(&tenants, "a.com"), Some(code("v1")));
+
+        // the oldest deployments beyond the retention are dropped, except the active one
+        for (hash, body) in [("h2", "v2"), ("h3", "v3"), ("h4", "v4")] {
+            deploy(hash, body)?;
+        }
+        let info = registry.get("app")?;
+        let hashes: Vec<_> = info.deployments.iter().map(|d| d.hash.as_str()).collect();
+        assert_eq!(hashes, ["h1", "h3", "h4"]);
+        assert_eq!(info.active.as_deref(), Some("h1"));
+        Ok(())
+    }
+
+    #[test]
+    fn registry_should_split_traffic() -> Result<()> {
+        let (registry, tenants) = registry(None)?;
+        let deploy = |hash: &str, body: &str, prod: bool| {
+            registry.deploy("app", with_hash(deploy_req(&["a.com"], body, prod), hash))
+        };
+        let split = |targets: &[(&str, u8)]| {
+            let deployments = targets
+                .iter()
+                .map(|(hash, weight)| SplitTarget {
+                    hash: hash.to_string(),
+                    rule: SplitRule {
+                        weight: *weight,
+                        ..Default::default()
+                    },
+                })
+                .collect();
+            registry.split("app", SplitRequest { deployments })
+        };
+        let chosen = || {
+            let tenants = tenants.load();
+            let tenant = tenants.resolve("a.com").unwrap();
+            let choice = tenant.split.map(|s| s.choose(&Default::default()));
+            let router = choice
+                .as_ref()
+                .and_then(|c| c.router)
+                .unwrap_or(tenant.router);
+            let deployment = choice.map(|c| c.deployment.to_string());
+            (deployment, router.load().code.clone())
+        };
+
+        deploy("h1", "v1", false)?;
+        assert!(matches!(split(&[("h1", 10)]), Err(AppError::Conflict(_))));
+        deploy("h2", "v2", true)?;
+        assert!(matches!(split(&[("h2", 10)]), Err(AppError::BadRequest(_))));
+        assert!(matches!(split(&[("h9", 10)]), Err(AppError::Conflict(_))));
+        assert!(matches!(
+            split(&[("h1", 200)]),
+            Err(AppError::BadRequest(_))
+        ));
+        assert_eq!(chosen(), (None, code("v2")));
+
+        let info = split(&[("h1", 100)])?;
+        assert_eq!(info.split[0].hash, "h1");
+        assert_eq!(chosen(), (Some("h1".to_string()), code("v1")));
+        // the split deployment is retained like the active one
+        deploy("h3", "v3", false)?;
+        let info = deploy("h4", "v4", false)?;
+        let hashes: Vec<_> = info.deployments.iter().map(|d| d.hash.as_str()).collect();
+        assert_eq!(hashes, ["h1", "h2", "h4"]);
+
+        // promoting a split deployment gives it all the traffic
+        let info = registry.promote(
+            "app",
+            PromoteRequest {
+                hash: "h1".to_string(),
+                hosts: vec![],
+            },
+        )?;
+        assert!(info.split.is_empty());
+        assert_eq!(chosen(), (None, code("v1")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admin_api_should_require_auth() -> Result<()> {
+        let (registry, _) = registry(None)?;
+        let auth: AuthConfig = serde_yaml::from_str("bearer: { tokens: [secret] }")?;
+        let app = app(Arc::new(registry), auth, Default::default());
+
+        let req = |token: &str| {
+            axum::http::Request::get("/projects")
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = app.clone().oneshot(req("wrong")).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = app.oneshot(req("secret")).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        Ok(())
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::ProjectConfig;
+
+// bumped on incompatible changes of the layout or the manifest
+pub const ARTIFACT_VERSION: u32 = 2;
+pub const ARTIFACT_MANIFEST: &str = "manifest.json";
+
+const BUNDLE: &str = "main.mjs";
+const SOURCE_MAP: &str = "main.mjs.map";
+const CONFIG: &str = "config.yml";
+const ASSETS: &str = "assets";
+const FILES: &str = "files";
+
+// the build output of a project, a directory holding the files listed in its manifest:
+//
+//   manifest.json
+//   main.mjs
+//   main.mjs.map
+//   config.yml
+//   assets/...
+//   files/...      the files the config refers to, e.g. a jwks
+//
+// the manifest is deployed along with the files
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    dir: PathBuf,
+    pub manifest: ArtifactManifest,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArtifactManifest {
+    pub version: u32,
+    // the project hash the artifact is built from
+    pub hash: String,
+    pub metadata: BuildMetadata,
+    // config.yml parsed, so readers don't need a yaml parser
+    pub config: serde_json::Value,
+    // every file of the artifact by its path relative to the artifact
+    pub files: BTreeMap<String, FileDigest>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildMetadata {
+    pub dino_version: String,
+    // unix timestamp in seconds
+    pub created_at: u64,
+    // the entry file relative to the project
+    pub entry: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileDigest {
+    pub blake3: String,
+    pub size: u64,
+}
+
+// the files of an artifact besides the bundle and config, by their path relative to the
+// artifact, e.g. the source map and the assets
+pub type ArtifactFiles = BTreeMap<String, Vec<u8>>;
+
+// what goes into a new artifact
+#[derive(Debug, Default)]
+pub struct ArtifactSource {
+    pub bundle: String,
+    pub source_map: Option<String>,
+    // the content of config.yml
+    pub config: String,
+    // (path relative to the assets dir, file to copy)
+    pub assets: Vec<(String, PathBuf)>,
+    // (path in the config, file to copy)
+    pub files: Vec<(String, PathBuf)>,
+}
+
+impl Artifact {
+    // the directory is written next to its final place and renamed, so a reader never sees a
+    // partial artifact
+    pub fn write(
+        dir: impl Into<PathBuf>,
+        hash: impl Into<String>,
+        metadata: BuildMetadata,
+        source: ArtifactSource,
+    ) -> Result<Self> {
+        let dir = dir.into();
+        let mut files = ArtifactFiles::new();
+        if let Some(source_map) = source.source_map {
+            files.insert(SOURCE_MAP.to_string(), source_map.into_bytes());
+        }
+        for (path, src) in &source.assets {
+            let path = format!("{}/{}", ASSETS, path);
+            let content = fs::read(src).with_context(|| format!("failed to read {}", path))?;
+            files.insert(path, content);
+        }
+        for (path, src) in &source.files {
+            let content = fs::read(src).with_context(|| format!("failed to read {}", path))?;
+            files.insert(config_file_path(path), content);
+        }
+        let manifest =
+            ArtifactManifest::new(hash, metadata, &source.bundle, &source.config, &files)?;
+        // fail the build rather than the deployment
+        let mut parsed: ProjectConfig = serde_json::from_value(manifest.config.clone())?;
+        parsed.load_files(|path| read_config_file(&files, path))?;
+        parsed.validate_handlers(&source.bundle)?;
+
+        let tmp = dir.with_extension("tmp");
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+        fs::write(tmp.join(BUNDLE), &source.bundle)?;
+        fs::write(tmp.join(CONFIG), &source.config)?;
+        for (path, content) in &files {
+            let filename = tmp.join(path);
+            if let Some(parent) = filename.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(filename, content)?;
+        }
+        fs::write(
+            tmp.join(ARTIFACT_MANIFEST),
+            serde_json::to_string_pretty(&manifest)?,
+        )?;
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::rename(&tmp, &dir)?;
+        Ok(Self { dir, manifest })
+    }
+
+    // read the manifest and make sure every file is there, untouched
+    pub fn load(dir: impl Into<PathBuf>) -> Result<Self> {
+        let dir = dir.into();
+        let filename = dir.join(ARTIFACT_MANIFEST);
+        let content = fs::read_to_string(&filename)
+            .with_context(|| format!("failed to read {}", filename.display()))?;
+        let manifest: ArtifactManifest = serde_json::from_str(&content)
+            .with_context(|| format!("invalid manifest {}", filename.display()))?;
+        let artifact = Self { dir, manifest };
+        let read = |path| {
+            fs::read_to_string(artifact.dir.join(path))
+                .with_context(|| format!("failed to read {} of {}", path, artifact.dir.display()))
+        };
+        let (bundle, config, files) = (read(BUNDLE)?, read(CONFIG)?, artifact.files()?);
+        artifact
+            .manifest
+            .verify(&bundle, &config, &files)
+            .with_context(|| format!("invalid artifact {}", artifact.dir.display()))?;
+        Ok(artifact)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn code(&self) -> Result<String> {
+        Ok(fs::read_to_string(self.dir.join(BUNDLE))?)
+    }
+
+    pub fn source_map(&self) -> Result<Option<String>> {
+        match self.manifest.files.contains_key(SOURCE_MAP) {
+            true => Ok(Some(fs::read_to_string(self.dir.join(SOURCE_MAP))?)),
+            false => Ok(None),
+        }
+    }
+
+    pub fn config(&self) -> Result<ProjectConfig> {
+        let mut config: ProjectConfig = serde_json::from_value(self.manifest.config.clone())?;
+        config.load_files(|path| Ok(fs::read(self.dir.join(config_file_path(path)))?))?;
+        Ok(config)
+    }
+
+    // the config.yml as written by the user
+    pub fn raw_config(&self) -> Result<String> {
+        Ok(fs::read_to_string(self.dir.join(CONFIG))?)
+    }
+
+    // paths relative to the assets dir
+    pub fn assets(&self) -> impl Iterator<Item = &str> {
+        let prefix = format!("{}/", ASSETS);
+        self.manifest
+            .files
+            .keys()
+            .filter_map(move |path| path.strip_prefix(&prefix))
+    }
+
+    // the files listed in the manifest besides the bundle and config, deployed along with them
+    pub fn files(&self) -> Result<ArtifactFiles> {
+        let mut files = ArtifactFiles::new();
+        for path in self.manifest.files.keys() {
+            if path == BUNDLE || path == CONFIG {
+                continue;
+            }
+            check_path(path)?;
+            let content = fs::read(self.dir.join(path))
+                .with_context(|| format!("failed to read {} of {}", path, self.dir.display()))?;
+            files.insert(path.clone(), content);
+        }
+        Ok(files)
+    }
+}
+
+impl ArtifactManifest {
+    // the manifest of the bundle, config and other files, written with them or deployed
+    // along with them
+    pub fn new(
+        hash: impl Into<String>,
+        metadata: BuildMetadata,
+        bundle: &str,
+        config: &str,
+        files: &ArtifactFiles,
+    ) -> Result<Self> {
+        let yaml: serde_yaml::Value = serde_yaml::from_str(config)?;
+        let mut digests = BTreeMap::new();
+        for (path, content) in files {
+            check_path(path)?;
+            if [BUNDLE, CONFIG, ARTIFACT_MANIFEST].contains(&path.as_str()) {
+                bail!("{} is reserved in the artifact", path);
+            }
+            digests.insert(path.clone(), FileDigest::new(content));
+        }
+        digests.insert(BUNDLE.to_string(), FileDigest::new(bundle.as_bytes()));
+        digests.insert(CONFIG.to_string(), FileDigest::new(config.as_bytes()));
+        Ok(Self {
+            version: ARTIFACT_VERSION,
+            hash: hash.into(),
+            metadata,
+            config: serde_json::to_value(yaml)?,
+            files: digests,
+        })
+    }
+
+    // the files are the ones the manifest was made for, untouched and none left out
+    pub fn verify(&self, bundle: &str, config: &str, files: &ArtifactFiles) -> Result<()> {
+        if self.version != ARTIFACT_VERSION {
+            bail!(
+                "unsupported artifact version {}, expect {}",
+                self.version,
+                ARTIFACT_VERSION
+            );
+        }
+        let expected = Self::new(&self.hash, self.metadata.clone(), bundle, config, files)?;
+        for (path, digest) in &self.files {
+            match expected.files.get(path) {
+                Some(expected) if expected == digest => {}
+                Some(_) => bail!("integrity check of {} failed", path),
+                None => bail!("{} is missing", path),
+            }
+        }
+        for path in expected.files.keys() {
+            if !self.files.contains_key(path) {
+                bail!("{} is not in the manifest", path);
+            }
+        }
+        if expected.config != self.config {
+            bail!("the parsed config differs from {}", CONFIG);
+        }
+        Ok(())
+    }
+}
+
+impl FileDigest {
+    fn new(content: &[u8]) -> Self {
+        Self {
+            blake3: blake3::hash(content).to_string(),
+            size: content.len() as u64,
+        }
+    }
+}
+
+// a file the config refers to, by its path in the config, from the files of an artifact
+pub(crate) fn read_config_file(files: &ArtifactFiles, path: &str) -> Result<Vec<u8>> {
+    match files.get(&config_file_path(path)) {
+        Some(content) => Ok(content.clone()),
+        None => bail!("{} is not in the artifact", path),
+    }
+}
+
+fn config_file_path(path: &str) -> String {
+    format!("{}/{}", FILES, path.trim_start_matches("./"))
+}
+
+// a manifest must not point outside of its artifact
+fn check_path(path: &str) -> Result<()> {
+    let normal = Path::new(path)
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if path.is_empty() || !normal {
+        bail!("invalid path {} in the artifact", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn artifact_should_roundtrip_and_check_integrity() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("dino-artifact-{}", std::process::id()));
+        fs::create_dir_all(&root)?;
+        let logo = root.join("logo.svg");
+        fs::write(&logo, "<svg/>")?;
+        let jwks = root.join("jwks.json");
+        fs::write(&jwks, r#"{"keys": []}"#)?;
+        let config = r#"
+name: app
+auth: { bearer: { tokens: [secret] }, jwt: { algorithm: RS256, jwks: ./keys/jwks.json } }
+rate_limit: { requests: 10, period: 60 }
+routes:
+  /:
+    - method: GET
+      handler: hello
+  /old:
+    - method: ANY
+      redirect: { to: / }
+"#;
+        let bundle = "(function(){async function hello(){}return{hello};})();";
+        let source = ArtifactSource {
+            bundle: bundle.to_string(),
+            source_map: Some("{}".to_string()),
+            config: config.to_string(),
+            assets: vec![("img/logo.svg".to_string(), logo)],
+            files: vec![("./keys/jwks.json".to_string(), jwks.clone())],
+        };
+        let metadata = BuildMetadata {
+            dino_version: "0.1.0".to_string(),
+            created_at: 1,
+            entry: "main.ts".to_string(),
+        };
+        let dir = root.join("abc");
+        Artifact::write(&dir, "abc", metadata.clone(), source)?;
+
+        let artifact = Artifact::load(&dir)?;
+        assert_eq!(artifact.manifest.hash, "abc");
+        assert_eq!(artifact.manifest.metadata, metadata);
+        assert_eq!(artifact.config()?.routes.len(), 2);
+        let auth = artifact.config()?.auth.unwrap();
+        assert!(auth.jwt.unwrap().jwk_set.is_some());
+        assert_eq!(artifact.raw_config()?, config);
+        assert_eq!(artifact.source_map()?.as_deref(), Some("{}"));
+        assert_eq!(artifact.assets().collect::<Vec<_>>(), ["img/logo.svg"]);
+
+        // the manifest only vouches for the files it was made for, wherever they are
+        let manifest = &artifact.manifest;
+        let files = artifact.files()?;
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            [
+                "assets/img/logo.svg",
+                "files/keys/jwks.json",
+                "main.mjs.map"
+            ]
+        );
+        assert!(manifest.verify(bundle, config, &files).is_ok());
+        assert!(
+            manifest
+                .verify("(function(){})();", config, &files)
+                .is_err()
+        );
+        let mut forged = manifest.clone();
+        forged.config["name"] = "other".into();
+        assert!(forged.verify(bundle, config, &files).is_err());
+        let mut missing = files.clone();
+        missing.remove("main.mjs.map");
+        let err = manifest.verify(bundle, config, &missing).unwrap_err();
+        assert_eq!(err.to_string(), "main.mjs.map is missing");
+        let mut extra = files.clone();
+        extra.insert("assets/x.txt".to_string(), vec![]);
+        assert!(manifest.verify(bundle, config, &extra).is_err());
+        extra.insert("../x.txt".to_string(), vec![]);
+        assert!(manifest.verify(bundle, config, &extra).is_err());
+
+        // an artifact of an older format is rebuilt rather than trusted
+        let mut old = manifest.clone();
+        old.version = 1;
+        let err = old.verify(bundle, config, &files).unwrap_err();
+        assert!(err.to_string().contains("unsupported artifact version 1"));
+
+        fs::write(dir.join("assets/img/logo.svg"), "<svg></svg>")?;
+        let err = Artifact::load(&dir).unwrap_err();
+        assert!(format!("{:#}", err).contains("integrity check of assets/img/logo.svg"));
+        fs::write(dir.join("assets/img/logo.svg"), "<svg/>")?;
+        fs::write(dir.join("config.yml"), format!("{}\n", config))?;
+        let err = Artifact::load(&dir).unwrap_err();
+        assert!(format!("{:#}", err).contains("integrity check of config.yml"));
+
+        // nor one missing a file the config refers to
+        let source = ArtifactSource {
+            bundle: bundle.to_string(),
+            config: config.to_string(),
+            ..Default::default()
+        };
+        let err = Artifact::write(root.join("bad"), "bad", metadata.clone(), source).unwrap_err();
+        assert!(format!("{:#}", err).contains("./keys/jwks.json is not in the artifact"));
+
+        // a bundle missing a configured handler is never written
+        let source = ArtifactSource {
+            bundle: "(function(){return{};})();".to_string(),
+            config: config.to_string(),
+            files: vec![("./keys/jwks.json".to_string(), jwks)],
+            ..Default::default()
+        };
+        assert!(Artifact::write(root.join("bad"), "bad", metadata, source).is_err());
+        assert!(!root.join("bad").exists());
+
+        fs::remove_dir_all(root)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,264 @@
+use axum::http::{HeaderMap, header::AUTHORIZATION};
+use base64::{Engine as _, engine::general_purpose::STANDARD};
+use bcrypt::HashParts;
+use jsonwebtoken::{DecodingKey, Validation, decode, decode_header};
+
+use crate::{
+    config::{AuthConfig, BasicAuthConfig, BearerAuthConfig, JwtAuthConfig},
+    engine::{AuthInfo, JsonValue},
+    error::AppError,
+    utils::constant_time_eq,
+};
+
+// a valid salt and hash that no password is expected to match
+const DUMMY_SALT_AND_HASH: &str = "C6UzMDM.H6dfI/f/IKxGhuNv9xHOx6w6yE8ITlL6LnB7Mj1b2bD2i";
+
+impl AuthConfig {
+    // an auth config without any scheme doesn't protect anything
+    pub(crate) fn is_public(&self) -> bool {
+        self.basic.is_none() && self.bearer.is_none() && self.jwt.is_none()
+    }
+
+    // verify the credentials of the request, None if the config doesn't require any
+    pub async fn verify(&self, headers: &HeaderMap) -> Result<Option<AuthInfo>, AppError> {
+        if self.is_public() {
+            return Ok(None);
+        }
+
+        let credentials = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split_once(' '));
+        let reason = match credentials {
+            Some((scheme, value)) => match self.check(scheme, value.trim()).await {
+                Ok(info) => return Ok(Some(info)),
+                Err(reason) => reason,
+            },
+            None => "missing credentials".to_string(),
+        };
+        Err(AppError::Unauthorized {
+            challenge: self.challenge(),
+            reason,
+        })
+    }
+
+    async fn check(&self, scheme: &str, value: &str) -> Result<AuthInfo, String> {
+        if scheme.eq_ignore_ascii_case("basic") {
+            return match &self.basic {
+                Some(basic) => basic.verify(value).await,
+                None => Err("basic auth is not allowed".to_string()),
+            };
+        }
+        if scheme.eq_ignore_ascii_case("bearer") {
+            // a bearer token is either one of the static tokens or a jwt
+            if let Some(bearer) = &self.bearer {
+                if bearer.verify(value) {
+                    return Ok(AuthInfo {
+                        scheme: "bearer".to_string(),
+                        subject: None,
+                        claims: JsonValue::default(),
+                    });
+                }
+            }
+            return match &self.jwt {
+                Some(jwt) => jwt.verify(value),
+                None => Err("invalid bearer token".to_string()),
+            };
+        }
+        Err(format!("unsupported auth scheme: {}", scheme))
+    }
+
+    fn challenge(&self) -> String {
+        match &self.basic {
+            Some(basic) => format!("Basic realm=\"{}\"", basic.realm),
+            None => "Bearer".to_string(),
+        }
+    }
+}
+
+impl BasicAuthConfig {
+    async fn verify(&self, value: &str) -> Result<AuthInfo, String> {
+        let invalid = || "invalid username or password".to_string();
+        let decoded = STANDARD.decode(value).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (user, password) = decoded.split_once(':').ok_or_else(invalid)?;
+        // an unknown user is checked against a dummy hash of the same cost, so the
+        // response time doesn't tell which users exist
+        let (hash, known) = match self.users.get(user) {
+            Some(hash) => (hash.clone(), true),
+            None => (self.dummy_hash(), false),
+        };
+        // bcrypt is slow on purpose, keep it off the async workers
+        let password = password.to_string();
+        let matched = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash))
+            .await
+            .map_err(|e| e.to_string())?
+            .unwrap_or(false);
+        if !(matched && known) {
+            return Err(invalid());
+        }
+
+        Ok(AuthInfo {
+            scheme: "basic".to_string(),
+            subject: Some(user.to_string()),
+            claims: JsonValue::default(),
+        })
+    }
+
+    fn dummy_hash(&self) -> String {
+        let cost = self
+            .users
+            .values()
+            .find_map(|hash| hash.parse::<HashParts>().ok())
+            .map_or(bcrypt::DEFAULT_COST, |parts| parts.get_cost());
+        format!("$2b${:02}${}", cost, DUMMY_SALT_AND_HASH)
+    }
+}
+
+impl BearerAuthConfig {
+    fn verify(&self, token: &str) -> bool {
+        self.tokens
+            .iter()
+            .any(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+    }
+}
+
+impl JwtAuthConfig {
+    fn verify(&self, token: &str) -> Result<AuthInfo, String> {
+        let key = self.decoding_key(token)?;
+        let mut validation = Validation::new(self.algorithm);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let data = decode::<serde_json::Value>(token, &key, &validation)
+            .map_err(|e| format!("invalid jwt: {}", e))?;
+        let subject = data
+            .claims
+            .get("sub")
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string());
+        Ok(AuthInfo {
+            scheme: "jwt".to_string(),
+            subject,
+            claims: JsonValue(data.claims),
+        })
+    }
+
+    fn decoding_key(&self, token: &str) -> Result<DecodingKey, String> {
+        if let Some(jwks) = &self.jwk_set {
+            let header = decode_header(token).map_err(|e| format!("invalid jwt: {}", e))?;
+            let jwk = match &header.kid {
+                Some(kid) => jwks.find(kid),
+                None => jwks.keys.first(),
+            }
+            .ok_or_else(|| "no matching key in jwks".to_string())?;
+            return DecodingKey::from_jwk(jwk).map_err(|e| format!("invalid jwk: {}", e));
+        }
+        match &self.secret {
+            Some(secret) => Ok(DecodingKey::from_secret(secret.as_bytes())),
+            None => Err("jwt auth needs either a secret or a jwks".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+    use serde_json::json;
+
+    use super::*;
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    async fn basic_and_bearer_should_work() {
+        let config = AuthConfig {
+            basic: Some(BasicAuthConfig {
+                realm: "dino".to_string(),
+                users: HashMap::from([("alice".to_string(), bcrypt::hash("secret", 4).unwrap())]),
+            }),
+            bearer: Some(BearerAuthConfig {
+                tokens: vec!["token1".to_string()],
+            }),
+            jwt: None,
+        };
+
+        let basic = format!("Basic {}", STANDARD.encode("alice:secret"));
+        let info = config.verify(&headers(&basic)).await.unwrap().unwrap();
+        assert_eq!(info.scheme, "basic");
+        assert_eq!(info.subject.as_deref(), Some("alice"));
+
+        let info = config
+            .verify(&headers("Bearer token1"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.scheme, "bearer");
+
+        let basic = format!("Basic {}", STANDARD.encode("alice:wrong"));
+        assert!(config.verify(&headers(&basic)).await.is_err());
+        let basic = format!("Basic {}", STANDARD.encode("bob:secret"));
+        assert!(config.verify(&headers(&basic)).await.is_err());
+        let dummy = config.basic.as_ref().unwrap().dummy_hash();
+        assert!(dummy.starts_with("$2b$04$"));
+        assert!(!bcrypt::verify("secret", &dummy).unwrap());
+        let err = config.verify(&HeaderMap::new()).await.unwrap_err();
+        assert!(matches!(
+            err,
+            AppError::Unauthorized { challenge, .. } if challenge == "Basic realm=\"dino\""
+        ));
+
+        assert!(
+            AuthConfig::default()
+                .verify(&HeaderMap::new())
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn jwt_should_work() {
+        let config = AuthConfig {
+            jwt: Some(JwtAuthConfig {
+                algorithm: Algorithm::HS256,
+                secret: Some("secret".to_string()),
+                jwks: None,
+                jwk_set: None,
+                issuer: Some("dino".to_string()),
+                audience: None,
+            }),
+            ..Default::default()
+        };
+        let token = |iss: &str| {
+            let claims = json!({"sub": "bob", "iss": iss, "exp": 4102444800u64, "role": "admin"});
+            let key = EncodingKey::from_secret(b"secret");
+            encode(&Header::default(), &claims, &key).unwrap()
+        };
+
+        let info = config
+            .verify(&headers(&format!("Bearer {}", token("dino"))))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.scheme, "jwt");
+        assert_eq!(info.subject.as_deref(), Some("bob"));
+        assert_eq!(info.claims.0["role"], "admin");
+
+        let ret = config
+            .verify(&headers(&format!("Bearer {}", token("other"))))
+            .await;
+        assert!(ret.is_err());
+    }
+}
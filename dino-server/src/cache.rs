@@ -0,0 +1,271 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use axum::http::{
+    HeaderMap, Method,
+    header::{AUTHORIZATION, CACHE_CONTROL},
+    request::Parts,
+};
+use lru::LruCache;
+
+use crate::{config::CacheConfig, engine::Res, path_and_query};
+
+pub const X_CACHE: &str = "x-cache";
+
+// status codes which are cacheable by default (RFC 9110 15.1)
+const CACHEABLE_STATUS: [u16; 11] = [200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+// in memory LRU cache for the responses of the js handlers, bounded by entries and body size
+pub struct ResponseCache {
+    config: CacheConfig,
+    inner: Mutex<CacheInner>,
+}
+
+struct CacheInner {
+    entries: LruCache<String, CacheEntry>,
+    // total size of the cached bodies
+    size: usize,
+}
+
+struct CacheEntry {
+    // request header name -> value the response was stored for
+    vary: Vec<(String, Option<String>)>,
+    res: Res,
+    stored: Instant,
+    ttl: Duration,
+    size: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        let cap = NonZeroUsize::new(config.max_entries).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            config,
+            inner: Mutex::new(CacheInner {
+                entries: LruCache::new(cap),
+                size: 0,
+            }),
+        }
+    }
+
+    // the cache key of the request, None if the request can't use the cache
+    pub fn key(host: &str, parts: &Parts) -> Option<String> {
+        if parts.method != Method::GET && parts.method != Method::HEAD {
+            return None;
+        }
+        let directives = cache_control(&parts.headers);
+        if directives
+            .iter()
+            .any(|d| d == "no-store" || d == "no-cache")
+        {
+            return None;
+        }
+        Some(format!(
+            "{} {} {}",
+            host,
+            parts.method,
+            path_and_query(&parts.uri)
+        ))
+    }
+
+    // the cached response and its age
+    pub fn get(&self, key: &str, req_headers: &HeaderMap) -> Option<(Res, Duration)> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        let age = entry.stored.elapsed();
+        let fresh = age < entry.ttl;
+        let matched = entry
+            .vary
+            .iter()
+            .all(|(name, value)| header_value(req_headers, name) == *value);
+        if fresh && matched {
+            return Some((entry.res.clone(), age));
+        }
+        if !fresh {
+            if let Some(entry) = inner.entries.pop(key) {
+                inner.size -= entry.size;
+            }
+        }
+        None
+    }
+
+    pub fn put(&self, key: String, req_headers: &HeaderMap, res: &Res) {
+        let Some(ttl) = ttl(req_headers, res) else {
+            return;
+        };
+        let vary = match res_header(res, "vary") {
+            Some(vary) if vary.trim() == "*" => return,
+            Some(vary) => vary
+                .split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let value = header_value(req_headers, &name);
+                    (name, value)
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let size = res.body.as_ref().map(|b| b.len()).unwrap_or_default();
+        if size > self.config.max_size {
+            return;
+        }
+
+        let entry = CacheEntry {
+            vary,
+            res: res.clone(),
+            stored: Instant::now(),
+            ttl,
+            size,
+        };
+        let mut inner = self.inner.lock().unwrap();
+        inner.size += size;
+        if let Some((_, old)) = inner.entries.push(key, entry) {
+            inner.size -= old.size;
+        }
+        while inner.size > self.config.max_size {
+            match inner.entries.pop_lru() {
+                Some((_, old)) => inner.size -= old.size,
+                None => break,
+            }
+        }
+    }
+}
+
+// how long the response can be served from a shared cache, None if it can't be stored
+fn ttl(req_headers: &HeaderMap, res: &Res) -> Option<Duration> {
+    if !CACHEABLE_STATUS.contains(&res.status) || res_header(res, "set-cookie").is_some() {
+        return None;
+    }
+    let directives: Vec<String> = res_header(res, "cache-control")
+        .map(parse_cache_control)
+        .unwrap_or_default();
+    if directives
+        .iter()
+        .any(|d| d == "no-store" || d == "private" || d == "no-cache")
+    {
+        return None;
+    }
+
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find_map(|d| d.strip_prefix(name)?.strip_prefix('=')?.parse::<u64>().ok())
+    };
+    let s_maxage = seconds("s-maxage");
+    // responses to authorized requests are only shared if explicitly allowed (RFC 9111 3.5)
+    if req_headers.contains_key(AUTHORIZATION)
+        && s_maxage.is_none()
+        && !directives.iter().any(|d| d == "public")
+    {
+        return None;
+    }
+
+    let ttl = s_maxage.or_else(|| seconds("max-age"))?;
+    (ttl > 0).then(|| Duration::from_secs(ttl))
+}
+
+fn cache_control(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get(CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .map(parse_cache_control)
+        .unwrap_or_default()
+}
+
+fn parse_cache_control(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|d| d.trim().to_lowercase())
+        .filter(|d| !d.is_empty())
+        .collect()
+}
+
+fn header_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+// headers returned by js may use any casing
+fn res_header<'a>(res: &'a Res, name: &str) -> Option<&'a str> {
+    res.headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::http::Request;
+
+    use super::*;
+
+    fn res(cache_control: &str, body: &str) -> Res {
+        Res {
+            body: Some(body.to_string()),
+            headers: HashMap::from([
+                ("Cache-Control".to_string(), cache_control.to_string()),
+                ("vary".to_string(), "accept-language".to_string()),
+            ]),
+            status: 200,
+        }
+    }
+
+    fn headers(lang: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("accept-language", lang.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn cache_should_honor_cache_control_and_vary() {
+        let cache = ResponseCache::new(CacheConfig {
+            max_entries: 10,
+            max_size: 1024,
+        });
+        let (parts, _) = Request::get("/a?x=1").body(()).unwrap().into_parts();
+        let key = ResponseCache::key("localhost", &parts).unwrap();
+
+        cache.put(key.clone(), &headers("en"), &res("max-age=60", "hello"));
+        let (cached, _) = cache.get(&key, &headers("en")).unwrap();
+        assert_eq!(cached.body.as_deref(), Some("hello"));
+        assert!(cache.get(&key, &headers("fr")).is_none());
+
+        cache.put(
+            "no-store".to_string(),
+            &headers("en"),
+            &res("no-store", "x"),
+        );
+        assert!(cache.get("no-store", &headers("en")).is_none());
+
+        let (parts, _) = Request::post("/a").body(()).unwrap().into_parts();
+        assert!(ResponseCache::key("localhost", &parts).is_none());
+    }
+
+    #[test]
+    fn cache_should_evict_by_size() {
+        let cache = ResponseCache::new(CacheConfig {
+            max_entries: 10,
+            max_size: 10,
+        });
+        cache.put(
+            "a".to_string(),
+            &headers("en"),
+            &res("max-age=60", "123456"),
+        );
+        cache.put(
+            "b".to_string(),
+            &headers("en"),
+            &res("max-age=60", "123456"),
+        );
+        assert!(cache.get("a", &headers("en")).is_none());
+        assert!(cache.get("b", &headers("en")).is_some());
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak, mpsc},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Result, bail};
+use indexmap::IndexMap;
+use rquickjs::{Ctx, Function, Object};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::warn;
+
+// across all the caches of a tenant, a put over them fails like a full quota does in browsers
+const MAX_ENTRIES: usize = 10_000;
+const MAX_SIZE: usize = 64 * 1024 * 1024;
+// changes are saved together once they stop for this long
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+// the Web Cache API (`caches.open(name)` etc.) on top of the native storage.
+// requests are urls or `{ method, url, headers }`, responses are `{ status, headers, body }`
+// like the ones the handlers return. Only the request headers named in `vary` are stored.
+const CACHES: &str = r#"
+(function(native){
+  const toRequest = (req) => typeof req === "string"
+    ? { method: "GET", url: req, headers: {} }
+    : { method: String(req.method ?? "GET").toUpperCase(), url: String(req.url), headers: req.headers ?? {} };
+  const header = (headers, name) => {
+    for (const k in headers ?? {}) if (k.toLowerCase() === name) return String(headers[k]);
+    return undefined;
+  };
+  const varyNames = (res) => (header(res.headers, "vary") ?? "")
+    .split(",").map((s) => s.trim().toLowerCase()).filter(Boolean);
+
+  class Cache {
+    #name;
+    constructor(name) { this.#name = name; }
+    async match(req, options) {
+      return (await this.matchAll(req, options))[0];
+    }
+    async matchAll(req, options = {}) {
+      const r = toRequest(req);
+      if (r.method !== "GET" && !options.ignoreMethod) return [];
+      return native.match(this.#name, r.url, !!options.ignoreSearch)
+        .map(([stored, res]) => [JSON.parse(stored), JSON.parse(res)])
+        .filter(([stored, res]) => options.ignoreVary
+          || varyNames(res).every((n) => stored[n] === header(r.headers, n)))
+        .map(([, res]) => res);
+    }
+    async put(req, res) {
+      const r = toRequest(req);
+      if (r.method !== "GET") throw new TypeError(`cannot cache a ${r.method} request`);
+      const status = res.status ?? 200;
+      if (status === 206) throw new TypeError("cannot cache a partial response");
+      const vary = varyNames(res);
+      if (vary.includes("*")) throw new TypeError("cannot cache a response with `vary: *`");
+      const stored = {};
+      for (const n of vary) {
+        const v = header(r.headers, n);
+        if (v !== undefined) stored[n] = v;
+      }
+      const value = { status, headers: res.headers ?? {}, body: res.body };
+      if (!native.put(this.#name, r.url, JSON.stringify(stored), JSON.stringify(value))) {
+        const e = new Error("the caches are full");
+        e.name = "QuotaExceededError";
+        throw e;
+      }
+    }
+    async delete(req, options = {}) {
+      const r = toRequest(req);
+      if (r.method !== "GET" && !options.ignoreMethod) return false;
+      return native.remove(this.#name, r.url, !!options.ignoreSearch);
+    }
+    async keys() {
+      return native.urls(this.#name).map((url) => ({ method: "GET", url, headers: {} }));
+    }
+  }
+
+  return {
+    open: async (name) => {
+      native.open(String(name));
+      return new Cache(String(name));
+    },
+    has: async (name) => native.has(String(name)),
+    delete: async (name) => native.delete(String(name)),
+    keys: async () => native.keys(),
+    match: async (req, options) => {
+      for (const name of native.keys()) {
+        const res = await new Cache(name).match(req, options);
+        if (res !== undefined) return res;
+      }
+      return undefined;
+    },
+  };
+})
+"#;
+
+// named caches of a tenant, shared by all the workers and kept across swaps
+pub struct CacheStorage {
+    store: Mutex<Store>,
+    // the file the caches are saved to, shortly after they change. held while saving
+    path: Mutex<Option<PathBuf>>,
+    // wakes the thread saving the caches, it stops once the storage is dropped
+    changed: Mutex<Option<mpsc::Sender<()>>>,
+    max_entries: usize,
+    max_size: usize,
+}
+
+// cache name -> url -> entry, in insertion order like `cache.keys()` requires
+type Caches = BTreeMap<String, IndexMap<String, CacheEntry>>;
+
+// the caches with the running totals the limits are checked against
+#[derive(Default)]
+struct Store {
+    caches: Caches,
+    entries: usize,
+    // bytes of all the entries
+    size: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    // the request headers named in the `vary` of the response
+    vary: Value,
+    response: Value,
+    // bytes of the url, vary and response as given to `put`
+    #[serde(skip)]
+    size: usize,
+}
+
+impl Default for CacheStorage {
+    fn default() -> Self {
+        Self {
+            store: Default::default(),
+            path: Default::default(),
+            changed: Default::default(),
+            max_entries: MAX_ENTRIES,
+            max_size: MAX_SIZE,
+        }
+    }
+}
+
+impl CacheStorage {
+    // load the caches saved in the file, and keep saving them there
+    pub fn persist_to(self: &Arc<Self>, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        if path.exists() {
+            let mut caches: Caches = serde_json::from_slice(&fs::read(&path)?)?;
+            for (url, entry) in caches.values_mut().flat_map(|cache| cache.iter_mut()) {
+                entry.size =
+                    url.len() + entry.vary.to_string().len() + entry.response.to_string().len();
+            }
+            let store = Store::new(caches);
+            if store.entries > self.max_entries || store.size > self.max_size {
+                bail!("{} holds more than the caches can", path.display());
+            }
+            *self.store.lock().unwrap() = store;
+        }
+        *self.path.lock().unwrap() = Some(path);
+
+        let (tx, rx) = mpsc::channel();
+        *self.changed.lock().unwrap() = Some(tx);
+        let storage = Arc::downgrade(self);
+        thread::spawn(move || save_on_change(storage, rx));
+        Ok(())
+    }
+
+    // save the caches now, e.g. before the server exits
+    pub fn flush(&self) {
+        // one save at a time, they share the temporary file
+        let path = self.path.lock().unwrap();
+        let Some(path) = path.as_ref() else {
+            return;
+        };
+        // serialized under the lock of the caches, written without it
+        let data = serde_json::to_vec(&self.store.lock().unwrap().caches);
+        if let Err(e) = data
+            .map_err(anyhow::Error::from)
+            .and_then(|data| save(path, &data))
+        {
+            warn!("failed to save caches to {}: {:?}", path.display(), e);
+        }
+    }
+
+    // expose the storage to the js context as the global `caches`
+    pub(crate) fn install(self: &Arc<Self>, ctx: &Ctx<'_>) -> rquickjs::Result<()> {
+        let native = Object::new(ctx.clone())?;
+
+        let storage = self.clone();
+        let open = move |name: String| {
+            storage.update(|store| {
+                store.caches.entry(name).or_default();
+            })
+        };
+        native.set("open", Function::new(ctx.clone(), open)?)?;
+
+        let storage = self.clone();
+        let has = move |name: String| storage.store.lock().unwrap().caches.contains_key(&name);
+        native.set("has", Function::new(ctx.clone(), has)?)?;
+
+        let storage = self.clone();
+        let delete = move |name: String| {
+            storage.update(|store| match store.caches.remove(&name) {
+                Some(cache) => {
+                    cache.values().for_each(|entry| store.forget(entry));
+                    true
+                }
+                None => false,
+            })
+        };
+        native.set("delete", Function::new(ctx.clone(), delete)?)?;
+
+        let storage = self.clone();
+        let keys = move || -> Vec<String> {
+            let store = storage.store.lock().unwrap();
+            store.caches.keys().cloned().collect()
+        };
+        native.set("keys", Function::new(ctx.clone(), keys)?)?;
+
+        let storage = self.clone();
+        let urls = move |name: String| -> Vec<String> {
+            let store = storage.store.lock().unwrap();
+            store
+                .caches
+                .get(&name)
+                .map(|cache| cache.keys().cloned().collect())
+                .unwrap_or_default()
+        };
+        native.set("urls", Function::new(ctx.clone(), urls)?)?;
+
+        let storage = self.clone();
+        // false if the caches are full
+        let put = move |name: String, url: String, vary: String, response: String| {
+            let entry = CacheEntry {
+                size: url.len() + vary.len() + response.len(),
+                vary: serde_json::from_str(&vary).unwrap_or_default(),
+                response: serde_json::from_str(&response).unwrap_or_default(),
+            };
+            storage.update(|store| {
+                let (mut entries, mut size) = (store.entries, store.size);
+                let replaced = store.caches.get(&name).and_then(|cache| cache.get(&url));
+                if let Some(replaced) = replaced {
+                    entries -= 1;
+                    size -= replaced.size;
+                }
+                if entries + 1 > storage.max_entries || size + entry.size > storage.max_size {
+                    return false;
+                }
+                (store.entries, store.size) = (entries + 1, size + entry.size);
+                let cache = store.caches.entry(name).or_default();
+                // a put replaces the entry and moves it to the end
+                cache.shift_remove(&url);
+                cache.insert(url, entry);
+                true
+            })
+        };
+        native.set("put", Function::new(ctx.clone(), put)?)?;
+
+        let storage = self.clone();
+        let matches = move |name: String, url: String, ignore_search: bool| -> Vec<Vec<String>> {
+            let store = storage.store.lock().unwrap();
+            let Some(cache) = store.caches.get(&name) else {
+                return vec![];
+            };
+            cache
+                .iter()
+                .filter(|(key, _)| same_url(key, &url, ignore_search))
+                .map(|(_, entry)| vec![entry.vary.to_string(), entry.response.to_string()])
+                .collect()
+        };
+        native.set("match", Function::new(ctx.clone(), matches)?)?;
+
+        let storage = self.clone();
+        let remove = move |name: String, url: String, ignore_search: bool| {
+            storage.update(|store| {
+                let Some(cache) = store.caches.get_mut(&name) else {
+                    return false;
+                };
+                let len = cache.len();
+                let (entries, size) = (&mut store.entries, &mut store.size);
+                cache.retain(|key, entry| {
+                    let matched = same_url(key, &url, ignore_search);
+                    if matched {
+                        *entries -= 1;
+                        *size -= entry.size;
+                    }
+                    !matched
+                });
+                cache.len() != len
+            })
+        };
+        native.set("remove", Function::new(ctx.clone(), remove)?)?;
+
+        let glue: Function = ctx.eval(CACHES)?;
+        let caches: Object = glue.call((native,))?;
+        ctx.globals().set("caches", caches)
+    }
+
+    // change the caches and have them saved if persisted
+    fn update<T>(&self, f: impl FnOnce(&mut Store) -> T) -> T {
+        let ret = f(&mut self.store.lock().unwrap());
+        if let Some(changed) = self.changed.lock().unwrap().as_ref() {
+            let _ = changed.send(());
+        }
+        ret
+    }
+}
+
+// runs until the storage is dropped
+fn save_on_change(storage: Weak<CacheStorage>, changed: mpsc::Receiver<()>) {
+    while changed.recv().is_ok() {
+        // wait for the changes to stop, so a burst of puts is saved once
+        while changed.recv_timeout(SAVE_DELAY).is_ok() {}
+        let Some(storage) = storage.upgrade() else {
+            return;
+        };
+        storage.flush();
+    }
+}
+
+impl Store {
+    fn new(caches: Caches) -> Self {
+        let (entries, size) = caches
+            .values()
+            .flat_map(|cache| cache.values())
+            .fold((0, 0), |(entries, size), entry| {
+                (entries + 1, size + entry.size)
+            });
+        Self {
+            caches,
+            entries,
+            size,
+        }
+    }
+
+    // an entry was dropped from the caches
+    fn forget(&mut self, entry: &CacheEntry) {
+        self.entries -= 1;
+        self.size -= entry.size;
+    }
+}
+
+// written next to the file and renamed, a crash never leaves a partial file behind
+fn save(path: &Path, data: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn same_url(a: &str, b: &str, ignore_search: bool) -> bool {
+    if !ignore_search {
+        return a == b;
+    }
+    let strip = |s: &str| s.split_once('?').map_or(s, |(s, _)| s).to_string();
+    strip(a) == strip(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{JsWorker, Req};
+
+    const CODE: &str = r#"
+    (function(){
+      async function put(req){
+        const cache = await caches.open("v1");
+        await cache.put(req, { status: 200, headers: { vary: "accept" }, body: req.body });
+        await cache.put("/other?x=1", { status: 200, headers: {}, body: "other" });
+        return { status: 201, headers: {} };
+      }
+      async function get(req){
+        const cache = await caches.open("v1");
+        const res = await cache.match(req);
+        const search = await cache.match("/other", { ignoreSearch: true });
+        const keys = (await cache.keys()).map((r) => r.url).join(",");
+        return { status: res ? 200 : 404, headers: { "x-keys": keys, "x-other": search?.body ?? "" }, body: res?.body };
+      }
+      async function del(req){
+        const cache = await caches.open("v1");
+        const deleted = await cache.delete(req.url);
+        const names = await caches.keys();
+        return { status: deleted ? 200 : 404, headers: { "x-caches": names.join(",") } };
+      }
+      return { put, get, del };
+    })();
+    "#;
+
+    fn req(url: &str, accept: &str, body: &str) -> Req {
+        Req::builder()
+            .method("GET".to_string())
+            .url(url.to_string())
+            .headers(HashMap::from([("accept".to_string(), accept.to_string())]))
+            .body(body.to_string())
+            .build()
+    }
+
+    fn worker(storage: &Arc<CacheStorage>) -> Result<JsWorker> {
+        JsWorker::try_new(CODE)?.with_caches(storage.clone())
+    }
+
+    #[test]
+    fn caches_should_be_shared_by_workers() -> Result<()> {
+        let storage = Arc::new(CacheStorage::default());
+        let res = worker(&storage)?.run("put", req("/a", "text/plain", "hello"))?;
+        assert_eq!(res.status, 201);
+
+        let res = worker(&storage)?.run("get", req("/a", "text/plain", ""))?;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body.as_deref(), Some("hello"));
+        assert_eq!(res.headers["x-keys"], "/a,/other?x=1");
+        assert_eq!(res.headers["x-other"], "other");
+
+        // the response varies on accept
+        let res = worker(&storage)?.run("get", req("/a", "application/json", ""))?;
+        assert_eq!(res.status, 404);
+
+        let res = worker(&storage)?.run("del", req("/a", "", ""))?;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.headers["x-caches"], "v1");
+        let res = worker(&storage)?.run("del", req("/a", "", ""))?;
+        assert_eq!(res.status, 404);
+        Ok(())
+    }
+
+    #[test]
+    fn caches_should_be_persisted() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("dino-caches-{}.json", std::process::id()));
+        let storage = Arc::new(CacheStorage::default());
+        storage.persist_to(&path)?;
+        worker(&storage)?.run("put", req("/a", "text/plain", "hello"))?;
+        storage.flush();
+
+        let storage = Arc::new(CacheStorage::default());
+        storage.persist_to(&path)?;
+        let res = worker(&storage)?.run("get", req("/a", "text/plain", ""))?;
+        assert_eq!(res.body.as_deref(), Some("hello"));
+        assert_eq!(storage.store.lock().unwrap().entries, 2);
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn caches_should_be_bounded() -> Result<()> {
+        let storage = Arc::new(CacheStorage {
+            max_entries: 2,
+            ..Default::default()
+        });
+        worker(&storage)?.run("put", req("/a", "text/plain", "hello"))?;
+        // the put throws, /b isn't stored
+        assert!(
+            worker(&storage)?
+                .run("put", req("/b", "text/plain", "hello"))
+                .is_err()
+        );
+        let res = worker(&storage)?.run("get", req("/a", "text/plain", ""))?;
+        assert_eq!(res.headers["x-keys"], "/a,/other?x=1");
+
+        // replacing an entry takes no more room
+        worker(&storage)?.run("put", req("/a", "text/plain", "again"))?;
+        let size = storage.store.lock().unwrap().size;
+        // deleting one makes room
+        worker(&storage)?.run("del", req("/a", "text/plain", ""))?;
+        let store = storage.store.lock().unwrap();
+        assert_eq!(store.entries, 1);
+        assert!(store.size < size);
+        drop(store);
+        worker(&storage)?.run("put", req("/b", "text/plain", "hello"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,259 @@
+use std::io::{Read, Write};
+
+use anyhow::Result;
+use axum::{
+    body::{Body, Bytes, to_bytes},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{
+            ACCEPT_ENCODING, CACHE_CONTROL, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+        },
+    },
+    response::Response,
+};
+use flate2::{
+    Compression,
+    read::{DeflateDecoder, GzDecoder},
+    write::GzEncoder,
+};
+
+use crate::{config::CompressionConfig, error::AppError, utils::wildcard_match};
+
+// upper bound of a decompressed request body, guards against compression bombs
+const MAX_DECOMPRESSED_SIZE: u64 = 16 * 1024 * 1024;
+const BROTLI_QUALITY: u32 = 5;
+const ZSTD_LEVEL: i32 = 3;
+// bigger bodies are encoded off the async workers, it takes a while
+const BLOCKING_SIZE: usize = 64 * 1024;
+
+impl CompressionConfig {
+    // compress the response with the best encoding accepted by the client
+    pub async fn apply(&self, req_headers: &HeaderMap, res: Response) -> Result<Response> {
+        let Some(encoding) = self.negotiate(req_headers) else {
+            return Ok(res);
+        };
+        if !self.should_compress(&res) {
+            return Ok(res);
+        }
+
+        let (mut parts, body) = res.into_parts();
+        let body = to_bytes(body, usize::MAX).await?;
+        if body.len() < self.min_size {
+            return Ok(Response::from_parts(parts, Body::from(body)));
+        }
+
+        let compressed = match body.len() < BLOCKING_SIZE {
+            true => compress(&encoding, &body)?,
+            false => {
+                let encoding = encoding.clone();
+                tokio::task::spawn_blocking(move || compress(&encoding, &body)).await??
+            }
+        };
+        parts.headers.remove(CONTENT_LENGTH);
+        parts
+            .headers
+            .insert(CONTENT_ENCODING, HeaderValue::from_str(&encoding)?);
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+        Ok(Response::from_parts(parts, Body::from(compressed)))
+    }
+
+    // pick the accepted encoding with the highest q value, ties are broken by the config order
+    fn negotiate(&self, headers: &HeaderMap) -> Option<String> {
+        let accept = headers.get(ACCEPT_ENCODING)?.to_str().ok()?;
+        let accepted: Vec<(&str, f32)> = accept
+            .split(',')
+            .filter_map(|item| {
+                let mut parts = item.trim().split(';');
+                let name = parts.next()?.trim();
+                let q = parts
+                    .find_map(|p| p.trim().strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                Some((name, q))
+            })
+            .collect();
+
+        let q_of = |encoding: &str| {
+            accepted
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(encoding))
+                .or_else(|| accepted.iter().find(|(name, _)| *name == "*"))
+                .map(|(_, q)| *q)
+                .unwrap_or(0.0)
+        };
+
+        let mut best: Option<(&String, f32)> = None;
+        for encoding in &self.algorithms {
+            let q = q_of(encoding);
+            if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+                best = Some((encoding, q));
+            }
+        }
+        best.map(|(encoding, _)| encoding.clone())
+    }
+
+    fn should_compress(&self, res: &Response) -> bool {
+        let headers = res.headers();
+        // the handler already encoded the body
+        if headers.contains_key(CONTENT_ENCODING) {
+            return false;
+        }
+        if matches!(
+            res.status(),
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        ) {
+            return false;
+        }
+        let no_transform = headers
+            .get(CACHE_CONTROL)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("no-transform"));
+        if no_transform {
+            return false;
+        }
+
+        let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+            return false;
+        };
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types
+            .iter()
+            .any(|pattern| wildcard_match(pattern, mime))
+    }
+}
+
+fn compress(encoding: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let ret = match encoding {
+        "gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()?
+        }
+        "br" => {
+            let mut ret = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut ret, 4096, BROTLI_QUALITY, 22);
+                encoder.write_all(data)?;
+            }
+            ret
+        }
+        "zstd" => zstd::encode_all(data, ZSTD_LEVEL)?,
+        v => anyhow::bail!("unsupported encoding: {}", v),
+    };
+    Ok(ret)
+}
+
+// decode the request body according to its content-encoding header, the header is removed
+pub fn decompress_body(headers: &mut HeaderMap, body: Bytes) -> Result<Bytes, AppError> {
+    let Some(encoding) = headers.get(CONTENT_ENCODING) else {
+        return Ok(body);
+    };
+    let encoding = encoding.to_str().unwrap_or_default().trim().to_lowercase();
+    let reader: Box<dyn Read + '_> = match encoding.as_str() {
+        "identity" => Box::new(&body[..]),
+        "gzip" | "x-gzip" => Box::new(GzDecoder::new(&body[..])),
+        "deflate" => Box::new(DeflateDecoder::new(&body[..])),
+        "br" => Box::new(brotli::Decompressor::new(&body[..], 4096)),
+        "zstd" => Box::new(
+            zstd::Decoder::new(&body[..]).map_err(|e| AppError::BadRequest(e.to_string()))?,
+        ),
+        _ => return Err(AppError::UnsupportedEncoding(encoding)),
+    };
+
+    let mut ret = Vec::new();
+    reader
+        .take(MAX_DECOMPRESSED_SIZE + 1)
+        .read_to_end(&mut ret)
+        .map_err(|e| AppError::BadRequest(format!("invalid {} body: {}", encoding, e)))?;
+    if ret.len() as u64 > MAX_DECOMPRESSED_SIZE {
+        return Err(AppError::BadRequest(
+            "decompressed body is too large".to_string(),
+        ));
+    }
+
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(CONTENT_LENGTH);
+    Ok(ret.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::read::GzDecoder;
+
+    use super::*;
+
+    fn config() -> CompressionConfig {
+        serde_yaml::from_str("min_size: 10").unwrap()
+    }
+
+    fn headers(accept: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, accept.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn negotiate_should_respect_q_and_order() {
+        let config = config();
+        assert_eq!(config.negotiate(&headers("gzip, br")).unwrap(), "br");
+        assert_eq!(
+            config.negotiate(&headers("gzip, br;q=0.5")).unwrap(),
+            "gzip"
+        );
+        assert_eq!(config.negotiate(&headers("*")).unwrap(), "br");
+        assert_eq!(config.negotiate(&headers("*, br;q=0")).unwrap(), "zstd");
+        assert!(config.negotiate(&headers("identity")).is_none());
+        assert!(config.negotiate(&HeaderMap::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn apply_should_compress_allowed_responses() -> Result<()> {
+        let config = config();
+        let body = "hello world ".repeat(10);
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "application/json; charset=utf-8")
+            .body(Body::from(body.clone()))?;
+
+        let res = config.apply(&headers("gzip"), res).await?;
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        let compressed = to_bytes(res.into_body(), usize::MAX).await?;
+        let mut decoded = String::new();
+        GzDecoder::new(&compressed[..]).read_to_string(&mut decoded)?;
+        assert_eq!(decoded, body);
+
+        // already encoded or not allowed content types are left alone
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "image/png")
+            .body(Body::from(body.clone()))?;
+        let res = config.apply(&headers("gzip"), res).await?;
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+
+        // a big one is encoded the same
+        let body = "hello world ".repeat(BLOCKING_SIZE);
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .body(Body::from(body.clone()))?;
+        let res = config.apply(&headers("zstd"), res).await?;
+        assert_eq!(res.headers()[CONTENT_ENCODING], "zstd");
+        let compressed = to_bytes(res.into_body(), usize::MAX).await?;
+        assert_eq!(zstd::decode_all(&compressed[..])?, body.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn decompress_body_should_work() -> Result<()> {
+        let data = zstd::encode_all(&b"{\"a\":1}"[..], 3)?;
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, "zstd".parse()?);
+        let body = decompress_body(&mut headers, data.into())?;
+        assert_eq!(body, "{\"a\":1}");
+        assert!(headers.get(CONTENT_ENCODING).is_none());
+
+        headers.insert(CONTENT_ENCODING, "compress".parse()?);
+        let ret = decompress_body(&mut headers, Bytes::new());
+        assert!(matches!(ret, Err(AppError::UnsupportedEncoding(_))));
+        Ok(())
+    }
+}
@@ -1,50 +1,842 @@
-use std::{fs, path::Path};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt, fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
-use crate::ProjectRoutes;
-use anyhow::Result;
+use crate::{JsWorker, ProjectRoutes, TlsFiles};
+use anyhow::{Context, Result, bail};
 use axum::http::Method;
-use serde::{Deserialize, Deserializer};
+use jsonwebtoken::{Algorithm, jwk::JwkSet};
+use serde::{Deserialize, Deserializer, Serialize};
+
+// the encodings compression::compress can produce
+const COMPRESSION_ALGORITHMS: [&str; 3] = ["br", "zstd", "gzip"];
 
 #[allow(unused)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ProjectConfig {
     pub name: String,
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+    // run in order before the route handler
+    #[serde(default)]
+    pub middleware: Vec<MiddlewareConfig>,
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    // limit for the whole project, checked before the route level one
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+    // cache the responses of js handlers according to their cache-control header, the paths
+    // with middleware are never cached
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
     pub routes: ProjectRoutes,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct MiddlewareConfig {
+    // exported js function called with `(req, next)`
+    pub handler: String,
+    // only run for paths under the prefix, global if not set
+    #[serde(default)]
+    pub prefix: Option<String>,
+}
+
 #[allow(unused)]
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ProjectRoute {
     #[serde(deserialize_with = "deserialize_method")]
-    pub method: Method,
-    pub handler: String,
+    pub method: RouteMethod,
+    #[serde(flatten)]
+    pub action: RouteAction,
+    // overrides the project level cors policy for this route
+    #[serde(default)]
+    pub cors: Option<CorsConfig>,
+    // overrides the project level auth for this route, `auth: {}` makes the route public
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimitConfig>,
+    // the path pattern the route is registered under, filled in when building the router
+    #[serde(skip)]
+    pub path: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RouteMethod {
+    // `ANY` or `*`, matches every method not registered explicitly for the path
+    Any,
+    Method(Method),
+}
+
+// what a route does: call a js handler, redirect the client or rewrite to another path
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteAction {
+    Handler(String),
+    Redirect(RedirectConfig),
+    // internal rewrite, the target path may use the params of the route, e.g. `/new/{id}`
+    Rewrite(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedirectConfig {
+    // target path or url, may use the params of the route, e.g. `/new/{id}`
+    pub to: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct CorsConfig {
+    // exact origins or wildcard patterns, e.g. `https://*.example.com` or `*`
+    #[serde(default)]
+    pub allow_origins: Vec<String>,
+    // empty means echo the requested method back
+    #[serde(default)]
+    pub allow_methods: Vec<String>,
+    // `*` means echo the requested headers back
+    #[serde(default)]
+    pub allow_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    // seconds the preflight response can be cached by the browser
+    #[serde(default)]
+    pub max_age: Option<u64>,
+}
+
+// a request is accepted if any of the configured schemes accepts it
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub basic: Option<BasicAuthConfig>,
+    #[serde(default)]
+    pub bearer: Option<BearerAuthConfig>,
+    #[serde(default)]
+    pub jwt: Option<JwtAuthConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BasicAuthConfig {
+    #[serde(default = "default_realm")]
+    pub realm: String,
+    // username -> bcrypt hash of the password
+    pub users: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BearerAuthConfig {
+    pub tokens: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwtAuthConfig {
+    #[serde(default = "default_jwt_algorithm")]
+    pub algorithm: Algorithm,
+    // shared secret for HS256
+    #[serde(default)]
+    pub secret: Option<String>,
+    // path of a local JWKS file for RS256, relative to the config
+    #[serde(default)]
+    pub jwks: Option<String>,
+    // the keys of the jwks file, loaded together with the config
+    #[serde(skip)]
+    pub jwk_set: Option<JwkSet>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+}
+
+// token bucket refilled with `requests` tokens every `period` seconds
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitConfig {
+    #[serde(deserialize_with = "deserialize_positive")]
+    pub requests: u32,
+    #[serde(
+        default = "default_rate_limit_period",
+        deserialize_with = "deserialize_positive"
+    )]
+    pub period: u64,
+    // bucket capacity, defaults to `requests`
+    #[serde(default, deserialize_with = "deserialize_burst")]
+    pub burst: Option<u32>,
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+// what a bucket is keyed by: `ip`, `subject` (auth subject, falls back to ip) or `header:<name>`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum RateLimitKey {
+    #[default]
+    Ip,
+    Subject,
+    Header(String),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionConfig {
+    // supported: br, zstd, gzip; the order breaks ties between equally accepted encodings
+    #[serde(
+        default = "default_compression_algorithms",
+        deserialize_with = "deserialize_compression_algorithms"
+    )]
+    pub algorithms: Vec<String>,
+    // smaller bodies are sent as is
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: usize,
+    // mime types (wildcards allowed, e.g. `text/*`) worth compressing
+    #[serde(default = "default_compression_content_types")]
+    pub content_types: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default = "default_cache_max_entries")]
+    pub max_entries: usize,
+    // total bytes of the cached bodies
+    #[serde(default = "default_cache_max_size")]
+    pub max_size: usize,
+}
+
+// how the server listens, shared by all the tenants
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerConfig {
+    // listeners inherited through LISTEN_FDS are used as well
+    #[serde(default)]
+    pub listen: Vec<ListenAddr>,
+    // permissions of the unix sockets, e.g. `660`
+    #[serde(default)]
+    pub socket_mode: Option<SocketMode>,
+    // seconds to wait for the in-flight requests on shutdown
+    #[serde(
+        default = "default_shutdown_timeout",
+        deserialize_with = "deserialize_secs"
+    )]
+    pub shutdown_timeout: Duration,
+    // alias host -> the host it is served as
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    // serves the requests whose host matches no tenant
+    #[serde(default)]
+    pub default_host: Option<String>,
+    // deploy and remove projects at runtime, disabled if not set
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    // a line per request, disabled if not set
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+    // export the spans of the requests over otlp, disabled if not set
+    #[serde(default)]
+    pub telemetry: Option<TelemetryConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    // `stdout`, `stderr` or a file the lines are appended to, relative to the manifest
+    #[serde(default)]
+    pub destination: LogDestination,
+    // request headers never written as is, e.g. the ones carrying credentials
+    #[serde(default = "default_redacted_headers")]
+    pub redact: Vec<String>,
+    // query params never written as is, matched exactly, e.g. tokens in signed urls
+    #[serde(default = "default_redacted_query")]
+    pub redact_query: Vec<String>,
+}
+
+// common and combined are the apache ones, json has the request headers as well
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    Common,
+    #[default]
+    Combined,
+    Json,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "String")]
+pub enum LogDestination {
+    #[default]
+    Stdout,
+    Stderr,
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TelemetryConfig {
+    // the otlp/http traces endpoint of the collector, e.g. `http://localhost:4318/v1/traces`
+    pub endpoint: String,
+    // the `service.name` of the spans
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    // share of the traces started here that are exported, the caller decides for the others
+    #[serde(
+        default = "default_sample_ratio",
+        deserialize_with = "deserialize_sample_ratio"
+    )]
+    pub sample_ratio: f64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AdminConfig {
+    // a listener of its own, the admin api is never served to the tenants' clients. served
+    // with the tenants' certificates unless it's local
+    pub listen: ListenAddr,
+    // required for every admin request, at least one scheme must be configured
+    pub auth: AuthConfig,
+    // serve every deployment at `<project>-<hash>.<preview_domain>`, e.g. `preview.localhost`
+    #[serde(default)]
+    pub preview_domain: Option<String>,
+    // deployments kept per project for previews and rollbacks
+    #[serde(default = "default_retention")]
+    pub retention: usize,
+}
+
+// dino-server.yml, the projects served by a single server
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerManifest {
+    #[serde(flatten)]
+    pub server: ServerConfig,
+    pub projects: Vec<ManifestProject>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestProject {
+    // relative to the manifest
+    pub dir: PathBuf,
+    // the hostnames the project is served on
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub tls: Option<TlsFiles>,
+    // other builds sharing the traffic of the hosts, e.g. a canary, the project serves the rest
+    #[serde(default)]
+    pub split: Vec<ManifestSplit>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManifestSplit {
+    // relative to the manifest, its name identifies the deployment and must be a dns label
+    pub dir: PathBuf,
+    #[serde(flatten)]
+    pub rule: SplitRule,
+}
+
+// which requests a deployment takes from the primary one of its hosts
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SplitRule {
+    // percentage of the requests matching no rule, a client sticks to its deployment
+    #[serde(default)]
+    pub weight: u8,
+    // header -> value, a request carrying any of them is always served by the deployment
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    // cookie -> value, same as the headers
+    #[serde(default)]
+    pub cookies: HashMap<String, String>,
+}
+
+// `<ip>:<port>` (ipv6 in brackets) or `unix:<path>`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+// unix file mode in octal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct SocketMode(pub u32);
+
+impl ServerManifest {
+    // relative paths are resolved against the directory of the manifest
+    pub fn load(filename: impl AsRef<Path>) -> Result<Self> {
+        let filename = filename.as_ref();
+        let content = fs::read_to_string(filename)?;
+        let mut manifest: Self = serde_yaml::from_str(&content)?;
+        let base = filename.parent().unwrap_or(Path::new(""));
+        for project in &mut manifest.projects {
+            project.dir = base.join(&project.dir);
+            for split in &mut project.split {
+                split.dir = base.join(&split.dir);
+            }
+            if let Some(tls) = &mut project.tls {
+                tls.cert = base.join(&tls.cert);
+                tls.key = base.join(&tls.key);
+            }
+        }
+        if let Some(admin) = &mut manifest.server.admin {
+            admin
+                .auth
+                .load_files(|path| Ok(fs::read(base.join(path))?))?;
+        }
+        if let Some(log) = &mut manifest.server.access_log {
+            if let LogDestination::File(path) = &mut log.destination {
+                *path = base.join(&path);
+            }
+        }
+        manifest.validate()?;
+        Ok(manifest)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut hosts = HashMap::new();
+        for project in &self.projects {
+            if project.hosts.is_empty() {
+                bail!("project {} has no hosts", project.dir.display());
+            }
+            for host in &project.hosts {
+                if let Some(dir) = hosts.insert(host.as_str(), &project.dir) {
+                    bail!(
+                        "host {} is used by both {} and {}",
+                        host,
+                        dir.display(),
+                        project.dir.display()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl ProjectConfig {
+    // relative paths are resolved against the directory of the config
     pub fn load(filename: impl AsRef<Path>) -> Result<Self> {
+        let filename = filename.as_ref();
         let content = fs::read_to_string(filename)?;
-        let config = serde_yaml::from_str(&content)?;
+        let mut config: Self = serde_yaml::from_str(&content)?;
+        let base = filename.parent().unwrap_or(Path::new(""));
+        config.load_files(|path| Ok(fs::read(base.join(path))?))?;
         Ok(config)
     }
+
+    // the files the config refers to by their path relative to it, e.g. a jwks
+    pub fn files(&self) -> BTreeSet<&str> {
+        let routes = self
+            .routes
+            .values()
+            .flatten()
+            .filter_map(|r| r.auth.as_ref());
+        self.auth
+            .iter()
+            .chain(routes)
+            .flat_map(|auth| auth.files())
+            .collect()
+    }
+
+    // read the files the config refers to, given their path
+    pub fn load_files(&mut self, read: impl Fn(&str) -> Result<Vec<u8>>) -> Result<()> {
+        let routes = self
+            .routes
+            .values_mut()
+            .flatten()
+            .filter_map(|r| r.auth.as_mut());
+        for auth in self.auth.iter_mut().chain(routes) {
+            auth.load_files(&read)?;
+        }
+        Ok(())
+    }
+
+    // make sure every handler used by the routes is a function exported by the bundle
+    pub fn validate_handlers(&self, code: &str) -> Result<()> {
+        self.validate_exports(JsWorker::try_new(code)?.exports()?)
+    }
+
+    // the same, the evaluation of the code is interrupted at the deadline
+    pub fn validate_handlers_until(&self, code: &str, deadline: Instant) -> Result<()> {
+        self.validate_exports(JsWorker::try_new_until(code, deadline)?.exports()?)
+    }
+
+    fn validate_exports(&self, exports: BTreeMap<String, &str>) -> Result<()> {
+        let mut errors = Vec::new();
+        let mut check = |location: String, name: &str| match exports.get(name) {
+            Some(&"function") => {}
+            Some(ty) => errors.push(format!(
+                "{}: handler `{}` is a {}, not a function",
+                location, name, ty
+            )),
+            None => errors.push(format!(
+                "{}: handler `{}` is not exported by the bundle",
+                location, name
+            )),
+        };
+
+        for middleware in &self.middleware {
+            let prefix = middleware.prefix.as_deref().unwrap_or("/");
+            check(format!("middleware {}", prefix), &middleware.handler);
+        }
+        for (path, routes) in &self.routes {
+            for route in routes {
+                if let RouteAction::Handler(name) = &route.action {
+                    check(format!("{} {}", route.method, path), name);
+                }
+            }
+        }
+        if !errors.is_empty() {
+            bail!("invalid handlers:\n  {}", errors.join("\n  "));
+        }
+        Ok(())
+    }
+}
+
+impl AuthConfig {
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.jwt.iter().filter_map(|jwt| jwt.jwks.as_deref())
+    }
+
+    pub fn load_files(&mut self, read: impl Fn(&str) -> Result<Vec<u8>>) -> Result<()> {
+        if let Some(jwt) = &mut self.jwt {
+            if let Some(path) = &jwt.jwks {
+                let content =
+                    read(path).with_context(|| format!("failed to read jwks {}", path))?;
+                let jwk_set = serde_json::from_slice(&content)
+                    .with_context(|| format!("invalid jwks {}", path))?;
+                jwt.jwk_set = Some(jwk_set);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MiddlewareConfig {
+    pub fn matches(&self, path: &str) -> bool {
+        let Some(prefix) = self.prefix.as_deref() else {
+            return true;
+        };
+        // `/api` matches `/api` and `/api/users` but not `/apis`
+        match path.strip_prefix(prefix.trim_end_matches('/')) {
+            Some(rest) => rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+}
+
+impl TryFrom<String> for RateLimitKey {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "ip" => Ok(RateLimitKey::Ip),
+            "subject" => Ok(RateLimitKey::Subject),
+            _ => match value.strip_prefix("header:") {
+                Some(name) if !name.is_empty() => Ok(RateLimitKey::Header(name.to_lowercase())),
+                _ => Err(format!("invalid rate limit key: {}", value)),
+            },
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![],
+            socket_mode: None,
+            shutdown_timeout: default_shutdown_timeout(),
+            aliases: HashMap::new(),
+            default_host: None,
+            admin: None,
+            access_log: None,
+            telemetry: None,
+        }
+    }
+}
+
+impl TelemetryConfig {
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            service_name: default_service_name(),
+            sample_ratio: default_sample_ratio(),
+        }
+    }
+}
+
+impl ListenAddr {
+    // loopback or a unix socket, not reachable from other hosts
+    pub fn is_local(&self) -> bool {
+        match self {
+            ListenAddr::Tcp(addr) => addr.ip().is_loopback(),
+            ListenAddr::Unix(_) => true,
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("invalid listen address: {}", s));
+            }
+            return Ok(ListenAddr::Unix(path.into()));
+        }
+        s.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| format!("invalid listen address: {}", s))
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for SocketMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match u32::from_str_radix(s.trim_start_matches("0o"), 8) {
+            Ok(mode) if mode <= 0o777 => Ok(SocketMode(mode)),
+            _ => Err(format!("invalid socket mode: {}", s)),
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        Self {
+            format: AccessLogFormat::default(),
+            destination: LogDestination::default(),
+            redact: default_redacted_headers(),
+            redact_query: default_redacted_query(),
+        }
+    }
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(AccessLogFormat::Common),
+            "combined" => Ok(AccessLogFormat::Combined),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!(
+                "invalid access log format: {}, expect common, combined or json",
+                s
+            )),
+        }
+    }
+}
+
+impl From<String> for LogDestination {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "stdout" => LogDestination::Stdout,
+            "stderr" => LogDestination::Stderr,
+            _ => LogDestination::File(value.into()),
+        }
+    }
+}
+
+impl TryFrom<String> for SocketMode {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+fn default_redacted_headers() -> Vec<String> {
+    [
+        "authorization",
+        "proxy-authorization",
+        "cookie",
+        "set-cookie",
+        "x-api-key",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+fn default_redacted_query() -> Vec<String> {
+    ["access_token", "api_key", "password", "token"]
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+fn default_service_name() -> String {
+    "dino-server".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+fn default_retention() -> usize {
+    10
+}
+
+fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn deserialize_secs<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+// a zero rate never refills the bucket, a zero period refills it at once
+fn deserialize_positive<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialEq,
+{
+    let value = T::deserialize(deserializer)?;
+    if value == T::default() {
+        return Err(serde::de::Error::custom("must be greater than 0"));
+    }
+    Ok(value)
+}
+
+// NaN is not in the range either
+fn deserialize_sample_ratio<'de, D>(deserializer: D) -> Result<f64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let ratio = f64::deserialize(deserializer)?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(serde::de::Error::custom(format!(
+            "invalid sample ratio {}, expect 0.0 to 1.0",
+            ratio
+        )));
+    }
+    Ok(ratio)
+}
+
+fn deserialize_burst<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_positive(deserializer).map(Some)
+}
+
+fn default_cache_max_entries() -> usize {
+    1024
+}
+
+fn default_cache_max_size() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_compression_algorithms() -> Vec<String> {
+    COMPRESSION_ALGORITHMS.map(String::from).to_vec()
+}
+
+// an unknown one would be negotiated with the clients and then fail every response
+fn deserialize_compression_algorithms<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let algorithms = Vec::<String>::deserialize(deserializer)?;
+    if let Some(name) = algorithms
+        .iter()
+        .find(|name| !COMPRESSION_ALGORITHMS.contains(&name.as_str()))
+    {
+        return Err(serde::de::Error::custom(format!(
+            "unsupported compression algorithm `{}`, expected one of {}",
+            name,
+            COMPRESSION_ALGORITHMS.join(", ")
+        )));
+    }
+    Ok(algorithms)
+}
+
+fn default_compression_min_size() -> usize {
+    1024
+}
+
+fn default_compression_content_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+fn default_rate_limit_period() -> u64 {
+    60
+}
+
+fn default_realm() -> String {
+    "dino".to_string()
+}
+
+fn default_jwt_algorithm() -> Algorithm {
+    Algorithm::HS256
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+impl fmt::Display for RouteMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteMethod::Any => write!(f, "ANY"),
+            RouteMethod::Method(method) => write!(f, "{}", method),
+        }
+    }
+}
+
+impl fmt::Display for RouteAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteAction::Handler(name) => write!(f, "handler {}", name),
+            RouteAction::Redirect(redirect) => {
+                write!(f, "redirect {} {}", redirect.status, redirect.to)
+            }
+            RouteAction::Rewrite(to) => write!(f, "rewrite {}", to),
+        }
+    }
 }
 
 // 自定义方法的反序列化 fn<'de, D>(D) -> Result<T, D::Error> where D: Deserializer<'de>
-fn deserialize_method<'de, D>(deserializer: D) -> Result<Method, D::Error>
+// any valid method token (e.g. `PROPFIND`, `PURGE`) is accepted, names are case-insensitive
+fn deserialize_method<'de, D>(deserializer: D) -> Result<RouteMethod, D::Error>
 where
     D: Deserializer<'de>,
 {
-    let s = String::deserialize(deserializer)?;
-    match s.to_uppercase().as_str() {
-        "GET" => Ok(Method::GET),
-        "POST" => Ok(Method::POST),
-        "PUT" => Ok(Method::PUT),
-        "DELETE" => Ok(Method::DELETE),
-        "PATCH" => Ok(Method::PATCH),
-        "HEAD" => Ok(Method::HEAD),
-        "OPTIONS" => Ok(Method::OPTIONS),
-        "CONNECT" => Ok(Method::CONNECT),
-        "TRACE" => Ok(Method::TRACE),
-        _ => Err(serde::de::Error::custom("invalid method")),
+    let s = String::deserialize(deserializer)?.to_uppercase();
+    match s.as_str() {
+        "ANY" | "*" => Ok(RouteMethod::Any),
+        _ => Method::from_bytes(s.as_bytes())
+            .map(RouteMethod::Method)
+            .map_err(|_| serde::de::Error::custom(format!("invalid method: {}", s))),
     }
 }
 
@@ -59,11 +851,227 @@ mod tests {
         let rdr = File::open("fixtures/config.yml")?;
         let config: ProjectConfig = serde_yaml::from_reader(rdr)?;
         assert_eq!(config.name, "dino-test");
-        assert_eq!(config.routes.len(), 2);
+        assert_eq!(config.routes.len(), 5);
         assert_eq!(config.routes.get("/api/hello/{id}").unwrap().len(), 2);
         assert_eq!(config.routes.get("/api/{name}/{id}").unwrap().len(), 2);
         println!("{:?}", config.routes);
 
+        let cors = config.cors.unwrap();
+        assert_eq!(cors.allow_origins, vec!["https://*.example.com"]);
+        assert!(cors.allow_credentials);
+        assert_eq!(cors.max_age, Some(600));
+        let routes = config.routes.get("/api/{name}/{id}").unwrap();
+        assert!(routes[0].cors.is_none());
+        assert_eq!(routes[1].cors.as_ref().unwrap().allow_origins, vec!["*"]);
+
+        let routes = config.routes.get("/old/{id}").unwrap();
+        assert!(matches!(
+            &routes[0].action,
+            RouteAction::Redirect(RedirectConfig { to, status: 308 }) if to == "/api/hello/{id}"
+        ));
+        assert!(matches!(
+            &routes[1].action,
+            RouteAction::Rewrite(to) if to == "/api/hello/{id}"
+        ));
+
+        assert_eq!(config.middleware.len(), 2);
+        assert!(config.middleware[0].matches("/api/hello/1"));
+        assert!(config.middleware[1].matches("/api/hello/1"));
+        assert!(!config.middleware[1].matches("/apis"));
+        assert!(!config.middleware[1].matches("/old/1"));
+
+        let routes = config.routes.get("/dav/{*path}").unwrap();
+        assert_eq!(
+            routes[0].method,
+            RouteMethod::Method(Method::from_bytes(b"PROPFIND")?)
+        );
+        assert_eq!(routes[1].method, RouteMethod::Any);
+
+        let routes = config.routes.get("/admin").unwrap();
+        let auth = routes[0].auth.as_ref().unwrap();
+        assert_eq!(auth.bearer.as_ref().unwrap().tokens, vec!["token1"]);
+        assert_eq!(auth.jwt.as_ref().unwrap().algorithm, Algorithm::HS256);
+
+        let rate_limit = config.rate_limit.unwrap();
+        assert_eq!(rate_limit.requests, 100);
+        assert_eq!(rate_limit.period, 60);
+        assert_eq!(rate_limit.key, RateLimitKey::Ip);
+        let compression = config.compression.unwrap();
+        assert_eq!(compression.min_size, 512);
+        assert_eq!(compression.algorithms, vec!["br", "zstd", "gzip"]);
+        let err = serde_yaml::from_str::<CompressionConfig>("{ algorithms: [gzip, deflate] }")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`deflate`"), "{}", err);
+
+        let rate_limit = routes[0].rate_limit.as_ref().unwrap();
+        assert_eq!(
+            rate_limit.key,
+            RateLimitKey::Header("x-api-key".to_string())
+        );
+
+        for yaml in [
+            "{ requests: 0 }",
+            "{ requests: 1, period: 0 }",
+            "{ requests: 1, burst: 0 }",
+        ] {
+            assert!(
+                serde_yaml::from_str::<RateLimitConfig>(yaml).is_err(),
+                "{}",
+                yaml
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn validate_handlers_should_report_bad_exports() -> Result<()> {
+        let config: ProjectConfig = serde_yaml::from_str(
+            r#"
+name: test
+routes:
+  /a:
+    - method: GET
+      handler: hello
+    - method: POST
+      handler: helo
+    - method: PUT
+      handler: version
+"#,
+        )?;
+        let code =
+            r#"(function(){async function hello(req){}return{hello:hello,version:"1"};})();"#;
+
+        let err = config.validate_handlers(code).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "invalid handlers:\n  POST /a: handler `helo` is not exported by the bundle\n  PUT /a: handler `version` is a string, not a function"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_method_should_be_rejected() {
+        let ret = serde_yaml::from_str::<ProjectRoute>("{method: 'GE T', handler: hello}");
+        assert!(ret.unwrap_err().to_string().contains("invalid method"));
+    }
+
+    #[test]
+    fn server_config_should_parse_listen_addrs() {
+        let config: ServerConfig = serde_yaml::from_str(
+            "listen: ['127.0.0.1:3000', '[::1]:3000', 'unix:/run/dino.sock']\nsocket_mode: '660'\nshutdown_timeout: 3",
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            vec![
+                ListenAddr::Tcp("127.0.0.1:3000".parse().unwrap()),
+                ListenAddr::Tcp("[::1]:3000".parse().unwrap()),
+                ListenAddr::Unix("/run/dino.sock".into()),
+            ]
+        );
+        assert_eq!(config.socket_mode, Some(SocketMode(0o660)));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(3));
+        assert!(config.listen.iter().all(ListenAddr::is_local));
+        assert!(!"0.0.0.0:3000".parse::<ListenAddr>().unwrap().is_local());
+
+        assert!("localhost:3000".parse::<ListenAddr>().is_err());
+        assert!("unix:".parse::<ListenAddr>().is_err());
+        assert!("888".parse::<SocketMode>().is_err());
+    }
+
+    #[test]
+    fn server_manifest_should_resolve_dirs() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("dino-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let filename = dir.join("dino-server.yml");
+        let yaml = r#"
+listen: ['127.0.0.1:3000']
+access_log: { format: json, destination: logs/access.log }
+telemetry: { endpoint: 'http://localhost:4318/v1/traces' }
+projects:
+  - dir: blog
+    hosts: [blog.localhost, blog.local]
+    tls: { cert: certs/blog.crt, key: certs/blog.key }
+  - dir: /srv/shop
+    hosts: [shop.localhost]
+    split:
+      - dir: shop-next
+        weight: 10
+        headers: { x-canary: "1" }
+"#;
+        fs::write(&filename, yaml)?;
+        let manifest = ServerManifest::load(&filename)?;
+        assert_eq!(manifest.server.listen.len(), 1);
+        let log = manifest.server.access_log.as_ref().unwrap();
+        assert_eq!(log.format, AccessLogFormat::Json);
+        assert_eq!(
+            log.destination,
+            LogDestination::File(dir.join("logs/access.log"))
+        );
+        assert!(log.redact.iter().any(|h| h == "authorization"));
+        let telemetry = manifest.server.telemetry.as_ref().unwrap();
+        assert_eq!(telemetry.service_name, "dino-server");
+        assert_eq!(telemetry.sample_ratio, 1.0);
+        let with_ratio = |ratio: &str| {
+            let yaml = format!("{{ endpoint: x, sample_ratio: {} }}", ratio);
+            serde_yaml::from_str::<TelemetryConfig>(&yaml)
+        };
+        for ratio in ["1.5", "-0.1", ".nan"] {
+            assert!(with_ratio(ratio).is_err());
+        }
+        assert_eq!(with_ratio("0.25")?.sample_ratio, 0.25);
+        assert_eq!(manifest.projects[0].dir, dir.join("blog"));
+        assert_eq!(
+            manifest.projects[0].tls.as_ref().unwrap().cert,
+            dir.join("certs/blog.crt")
+        );
+        assert_eq!(manifest.projects[1].dir, PathBuf::from("/srv/shop"));
+        let split = &manifest.projects[1].split[0];
+        assert_eq!(split.dir, dir.join("shop-next"));
+        assert_eq!(split.rule.weight, 10);
+        assert_eq!(split.rule.headers["x-canary"], "1");
+
+        fs::write(&filename, yaml.replace("shop.localhost", "blog.local"))?;
+        let err = ServerManifest::load(&filename).unwrap_err();
+        assert!(err.to_string().contains("host blog.local is used by both"));
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn project_config_should_load_files_relative_to_it() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("dino-project-{}", std::process::id()));
+        fs::create_dir_all(dir.join("keys"))?;
+        let filename = dir.join("config.yml");
+        fs::write(
+            &filename,
+            r#"
+name: app
+routes:
+  /:
+    - method: GET
+      handler: hello
+      auth: { jwt: { algorithm: RS256, jwks: keys/jwks.json } }
+"#,
+        )?;
+        // read when the config is loaded, not when it's parsed
+        let config: ProjectConfig = serde_yaml::from_str(&fs::read_to_string(&filename)?)?;
+        assert_eq!(
+            config.files().into_iter().collect::<Vec<_>>(),
+            ["keys/jwks.json"]
+        );
+        let err = ProjectConfig::load(&filename).unwrap_err();
+        assert!(format!("{:#}", err).contains("failed to read jwks keys/jwks.json"));
+
+        fs::write(dir.join("keys/jwks.json"), r#"{"keys": []}"#)?;
+        let config = ProjectConfig::load(&filename)?;
+        let auth = config.routes["/"][0].auth.as_ref().unwrap();
+        assert!(auth.jwt.as_ref().unwrap().jwk_set.is_some());
+
+        fs::remove_dir_all(dir)?;
         Ok(())
     }
 }
@@ -0,0 +1,160 @@
+use axum::{
+    body::Body,
+    http::{
+        HeaderMap, HeaderValue, Method, StatusCode,
+        header::{
+            ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+            ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN,
+            ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS,
+            ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+        },
+    },
+    response::Response,
+};
+
+use crate::{config::CorsConfig, utils::wildcard_match};
+
+impl CorsConfig {
+    fn allow_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let value = origin.to_str().ok()?;
+        if !self.allow_origins.iter().any(|p| wildcard_match(p, value)) {
+            return None;
+        }
+        // `*` can't be used together with credentials, echo the origin instead
+        let any = self.allow_origins.iter().all(|p| p == "*");
+        if any && !self.allow_credentials {
+            Some(HeaderValue::from_static("*"))
+        } else {
+            Some(origin.clone())
+        }
+    }
+
+    fn set_common_headers(&self, allow_origin: HeaderValue, headers: &mut HeaderMap) {
+        if allow_origin != "*" {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    // build the response for a preflight request, None if the request is not a preflight
+    pub fn preflight(&self, method: &Method, headers: &HeaderMap) -> Option<Response> {
+        if method != Method::OPTIONS {
+            return None;
+        }
+        let origin = headers.get(ORIGIN)?;
+        let request_method = headers.get(ACCESS_CONTROL_REQUEST_METHOD)?;
+
+        let mut res = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap();
+        // a disallowed origin gets a bare response, so the browser will block the request
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return Some(res);
+        };
+
+        let res_headers = res.headers_mut();
+        self.set_common_headers(allow_origin, res_headers);
+
+        let methods = if self.allow_methods.is_empty() {
+            Some(request_method.clone())
+        } else {
+            HeaderValue::from_str(&self.allow_methods.join(", ")).ok()
+        };
+        if let Some(methods) = methods {
+            res_headers.insert(ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+
+        let allow_headers = if self.allow_headers.iter().any(|h| h == "*") {
+            headers.get(ACCESS_CONTROL_REQUEST_HEADERS).cloned()
+        } else if !self.allow_headers.is_empty() {
+            HeaderValue::from_str(&self.allow_headers.join(", ")).ok()
+        } else {
+            None
+        };
+        if let Some(allow_headers) = allow_headers {
+            res_headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+        }
+
+        if let Some(max_age) = self.max_age {
+            res_headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.into());
+        }
+
+        Some(res)
+    }
+
+    // add the cors headers to the response of an actual (non-preflight) request
+    pub fn apply(&self, req_headers: &HeaderMap, res: &mut Response) {
+        let Some(origin) = req_headers.get(ORIGIN) else {
+            return;
+        };
+        let Some(allow_origin) = self.allow_origin(origin) else {
+            return;
+        };
+
+        let headers = res.headers_mut();
+        self.set_common_headers(allow_origin, headers);
+        if !self.expose_headers.is_empty() {
+            if let Ok(v) = HeaderValue::from_str(&self.expose_headers.join(", ")) {
+                headers.insert(ACCESS_CONTROL_EXPOSE_HEADERS, v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CorsConfig {
+        CorsConfig {
+            allow_origins: vec!["https://*.example.com".to_string()],
+            allow_methods: vec!["GET".to_string(), "POST".to_string()],
+            allow_headers: vec!["*".to_string()],
+            allow_credentials: true,
+            max_age: Some(600),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn preflight_should_work() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, "https://app.example.com".parse().unwrap());
+        headers.insert(ACCESS_CONTROL_REQUEST_METHOD, "POST".parse().unwrap());
+        headers.insert(ACCESS_CONTROL_REQUEST_HEADERS, "x-token".parse().unwrap());
+
+        assert!(config().preflight(&Method::GET, &headers).is_none());
+
+        let res = config().preflight(&Method::OPTIONS, &headers).unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let h = res.headers();
+        assert_eq!(h[ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.example.com");
+        assert_eq!(h[ACCESS_CONTROL_ALLOW_METHODS], "GET, POST");
+        assert_eq!(h[ACCESS_CONTROL_ALLOW_HEADERS], "x-token");
+        assert_eq!(h[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(h[ACCESS_CONTROL_MAX_AGE], "600");
+    }
+
+    #[test]
+    fn apply_should_skip_disallowed_origin() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ORIGIN, "https://evil.com".parse().unwrap());
+        let mut res = Response::new(Body::empty());
+        config().apply(&headers, &mut res);
+        assert!(res.headers().get(ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+
+        let config = CorsConfig {
+            allow_origins: vec!["*".to_string()],
+            ..Default::default()
+        };
+        config.apply(&headers, &mut res);
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    }
+}
@@ -1,11 +1,17 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Instant,
+};
 
 use anyhow::Result;
 use axum::{body::Body, response::Response};
 use dino_macro::{FromJs, IntoJs};
-use rquickjs::{Context, Function, Object, Promise, Runtime};
+use rquickjs::{Context, Ctx, Function, Object, Promise, Runtime, Value};
 use typed_builder::TypedBuilder;
 
+use crate::caches::CacheStorage;
+
 #[allow(unused)]
 pub struct JsWorker {
     rt: Runtime,
@@ -18,6 +24,9 @@ pub struct Req {
     pub method: String,
     #[builder(setter(into))]
     pub url: String,
+    // negotiated protocol, e.g. HTTP/1.1 or HTTP/2.0
+    #[builder(default = "HTTP/1.1".to_string(), setter(into))]
+    pub version: String,
     #[builder(default)]
     pub query: HashMap<String, String>,
     #[builder(default)]
@@ -26,30 +35,104 @@ pub struct Req {
     pub headers: HashMap<String, String>,
     #[builder(default, setter(strip_option))]
     pub body: Option<String>,
+    // the labels matched by the `*` of a wildcard host, e.g. `feat-1` for `*.preview.local`
+    #[builder(default)]
+    pub subdomain: Option<String>,
+    // set when the route is protected by an auth guard
+    #[builder(default)]
+    pub auth: Option<AuthInfo>,
+    // the x-request-id of the request, generated if the client didn't send one
+    #[builder(default, setter(into))]
+    pub request_id: String,
+    // `traceparent` (and `tracestate`) of the span running the handler, forwarding them on the
+    // outbound requests keeps those in the trace of the request
+    #[builder(default)]
+    pub trace_headers: HashMap<String, String>,
 }
 
+#[derive(Debug, Clone, IntoJs)]
+pub struct AuthInfo {
+    // basic, bearer or jwt
+    pub scheme: String,
+    // the basic auth user or the `sub` claim of the jwt
+    pub subject: Option<String>,
+    // validated jwt claims, null for the other schemes
+    pub claims: JsonValue,
+}
+
+// a json value which is passed into js as a plain js value
+#[derive(Debug, Clone, Default)]
+pub struct JsonValue(pub serde_json::Value);
+
 #[allow(unused)]
-#[derive(Debug, FromJs)]
+#[derive(Debug, Clone, FromJs)]
 pub struct Res {
     pub body: Option<String>,
     pub headers: HashMap<String, String>,
     pub status: u16,
 }
 
+// call the middleware in order with `(req, next)`, the route handler is the last one in the chain.
+// `next(req)` resolves to the response of the rest of the chain, `next()` keeps the current req.
+const DISPATCH: &str = r#"
+(function(){
+  return async function(middleware, name, req){
+    const dispatch = async (i, req) => {
+      if (i === middleware.length) return handlers[name](req);
+      return handlers[middleware[i]](req, (next) => dispatch(i + 1, next ?? req));
+    };
+    return dispatch(0, req);
+  };
+})();
+"#;
+
+// a minimal `addEventListener` for the lifecycle events, the listeners registered when
+// the module is evaluated are called with `{ type }` by `__dino_emit(type)`
+const EVENTS: &str = r#"
+(function(){
+  const listeners = {};
+  globalThis.addEventListener = (type, fn) => {
+    (listeners[type] ??= []).push(fn);
+  };
+  globalThis.removeEventListener = (type, fn) => {
+    listeners[type] = (listeners[type] ?? []).filter((f) => f !== fn);
+  };
+  return async function(type){
+    for (const fn of listeners[type] ?? []) await fn({ type });
+  };
+})();
+"#;
+
 fn print(msg: String) {
     println!("{}", msg);
 }
 
 impl JsWorker {
     pub fn try_new(module: &str) -> Result<Self> {
+        Self::build(module, None)
+    }
+
+    // the js is interrupted once the deadline passes, from the evaluation of the module on
+    pub fn try_new_until(module: &str, deadline: Instant) -> Result<Self> {
+        Self::build(module, Some(deadline))
+    }
+
+    fn build(module: &str, deadline: Option<Instant>) -> Result<Self> {
         let rt = Runtime::new()?;
+        if let Some(deadline) = deadline {
+            rt.set_interrupt_handler(Some(Box::new(move || Instant::now() >= deadline)));
+        }
         let ctx = Context::full(&rt)?;
 
         ctx.with(|ctx| {
             let global = ctx.globals();
 
+            let emit: Function = ctx.eval(EVENTS)?;
+            global.set("__dino_emit", emit)?;
             let ret: Object = ctx.eval(module)?;
             global.set("handlers", ret)?;
+            let dispatch: Function = ctx.eval(DISPATCH)?;
+            global.set("__dino_dispatch", dispatch)?;
             global.set(
                 "print",
                 Function::new(ctx.clone(), print)?.with_name("print")?,
@@ -61,6 +144,12 @@ impl JsWorker {
         Ok(Self { rt, ctx })
     }
 
+    // give the handlers access to the tenant's named caches via `caches`
+    pub fn with_caches(self, storage: Arc<CacheStorage>) -> Result<Self> {
+        self.ctx.with(|ctx| storage.install(&ctx))?;
+        Ok(self)
+    }
+
     pub fn run(&self, name: &str, req: Req) -> anyhow::Result<Res> {
         // self.ctx.with(|ctx| {
         //     ctx.eval_promise(code)?.finish::<()>()?;
@@ -76,6 +165,68 @@ impl JsWorker {
             Ok::<_, anyhow::Error>(v.finish()?)
         })
     }
+
+    // run the handler behind the given middleware chain
+    pub fn run_with_middleware(
+        &self,
+        middleware: &[String],
+        name: &str,
+        req: Req,
+    ) -> anyhow::Result<Res> {
+        if middleware.is_empty() {
+            return self.run(name, req);
+        }
+        self.ctx.with(|ctx| {
+            let dispatch: Function = ctx.globals().get("__dino_dispatch")?;
+            let v: Promise = dispatch.call((middleware.to_vec(), name, req))?;
+
+            Ok::<_, anyhow::Error>(v.finish()?)
+        })
+    }
+
+    // bytes allocated by the runtime, e.g. after running a handler
+    pub fn memory_used(&self) -> u64 {
+        self.rt.memory_usage().memory_used_size.max(0) as u64
+    }
+
+    // call the `unload` listeners, before the server exits
+    pub fn unload(&self) -> anyhow::Result<()> {
+        self.ctx.with(|ctx| {
+            let emit: Function = ctx.globals().get("__dino_emit")?;
+            let v: Promise = emit.call(("unload",))?;
+
+            Ok::<_, anyhow::Error>(v.finish()?)
+        })
+    }
+
+    // names of the functions exported by the bundle
+    pub fn handlers(&self) -> anyhow::Result<Vec<String>> {
+        let exports = self.exports()?;
+        Ok(exports
+            .into_iter()
+            .filter(|(_, ty)| *ty == "function")
+            .map(|(name, _)| name)
+            .collect())
+    }
+
+    // everything exported by the bundle, name -> js type name
+    pub fn exports(&self) -> anyhow::Result<BTreeMap<String, &'static str>> {
+        self.ctx.with(|ctx| {
+            let handlers: Object = ctx.globals().get("handlers")?;
+            let mut exports = BTreeMap::new();
+            for prop in handlers.props::<String, Value>() {
+                let (name, value) = prop?;
+                exports.insert(name, value.type_name());
+            }
+            Ok(exports)
+        })
+    }
+}
+
+impl<'js> rquickjs::IntoJs<'js> for JsonValue {
+    fn into_js(self, ctx: &Ctx<'js>) -> rquickjs::Result<Value<'js>> {
+        ctx.json_parse(self.0.to_string())
+    }
 }
 
 impl From<Res> for Response {
@@ -124,7 +275,92 @@ mod tests {
         let body_bytes = to_bytes(body, usize::MAX).await?;
         let body_string = String::from_utf8(body_bytes.to_vec())?;
         assert_eq!(body_string, "hello world");
+        assert_eq!(worker.handlers()?, vec!["hello"]);
 
         Ok(())
     }
+
+    #[test]
+    fn auth_info_should_be_passed_to_js() -> anyhow::Result<()> {
+        let code = r#"
+        (function(){
+            async function whoami(req){
+                return {headers:{},status:200,body:`${req.auth.subject}:${req.auth.claims.role}`};
+            }
+            return{whoami};
+        })();
+        "#;
+        let worker = JsWorker::try_new(code)?;
+        let req = Req::builder()
+            .method("GET")
+            .url("/")
+            .auth(Some(AuthInfo {
+                scheme: "jwt".to_string(),
+                subject: Some("bob".to_string()),
+                claims: JsonValue(serde_json::json!({"role": "admin"})),
+            }))
+            .build();
+        let res = worker.run("whoami", req)?;
+        assert_eq!(res.body.as_deref(), Some("bob:admin"));
+        Ok(())
+    }
+
+    #[test]
+    fn middleware_should_run_in_order() -> anyhow::Result<()> {
+        let code = r#"
+        (function(){
+            async function hello(req){return{headers:{},status:200,body:req.headers["x-user"]};}
+            async function auth(req, next){
+                if (!req.headers["authorization"]) return {headers:{},status:401,body:"denied"};
+                req.headers["x-user"] = "alice";
+                return next(req);
+            }
+            async function tag(req, next){
+                const res = await next();
+                res.headers["x-tag"] = "1";
+                return res;
+            }
+            return{hello,auth,tag};
+        })();
+        "#;
+        let worker = JsWorker::try_new(code)?;
+        let middleware = vec!["tag".to_string(), "auth".to_string()];
+
+        let req = Req::builder().method("GET").url("/").build();
+        let res = worker.run_with_middleware(&middleware, "hello", req)?;
+        assert_eq!(res.status, 401);
+        assert_eq!(res.headers["x-tag"], "1");
+
+        let req = Req::builder()
+            .method("GET")
+            .url("/")
+            .headers(HashMap::from([(
+                "authorization".to_string(),
+                "token".to_string(),
+            )]))
+            .build();
+        let res = worker.run_with_middleware(&middleware, "hello", req)?;
+        assert_eq!(res.status, 200);
+        assert_eq!(res.body.as_deref(), Some("alice"));
+        Ok(())
+    }
+
+    #[test]
+    fn unload_listeners_should_run() -> anyhow::Result<()> {
+        let code = r#"
+        (function(){
+            globalThis.unloaded = [];
+            addEventListener("unload", async (e) => { unloaded.push(e.type); });
+            const skipped = () => unloaded.push("skipped");
+            addEventListener("unload", skipped);
+            removeEventListener("unload", skipped);
+            return {};
+        })();
+        "#;
+        let worker = JsWorker::try_new(code)?;
+        worker.unload()?;
+        let unloaded: Vec<String> = worker.ctx.with(|ctx| ctx.globals().get("unloaded"))?;
+        assert_eq!(unloaded, vec!["unload"]);
+        Ok(())
+    }
 }
@@ -1,5 +1,8 @@
 use axum::{
-    http::{Method, StatusCode},
+    http::{
+        HeaderValue, Method, StatusCode,
+        header::{RETRY_AFTER, WWW_AUTHENTICATE},
+    },
     response::{IntoResponse, Response},
 };
 use thiserror::Error;
@@ -15,6 +18,31 @@ pub enum AppError {
     #[error("Method not found: {0}")]
     RouteMethodNotAllowed(Method),
 
+    #[error("Unauthorized: {reason}")]
+    Unauthorized { challenge: String, reason: String },
+
+    #[error("Rate limit exceeded, retry after {retry_after}s")]
+    RateLimited {
+        limit: u32,
+        period: u64,
+        retry_after: u64,
+    },
+
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+
+    #[error("Unsupported content encoding: {0}")]
+    UnsupportedEncoding(String),
+
+    #[error("Too many rewrites: {0}")]
+    TooManyRewrites(String),
+
+    #[error("Project not found: {0}")]
+    ProjectNotFound(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("Anyhow error: {0}")]
     Anyhow(#[from] anyhow::Error),
 
@@ -24,14 +52,44 @@ pub enum AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let code = match self {
+        let code = match &self {
             AppError::HostNotFound(_) => StatusCode::NOT_FOUND,
             AppError::RoutePathNotFound(_) => StatusCode::NOT_FOUND,
             AppError::RouteMethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::Unauthorized { .. } => StatusCode::UNAUTHORIZED,
+            AppError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::UnsupportedEncoding(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::TooManyRewrites(_) => StatusCode::LOOP_DETECTED,
+            AppError::ProjectNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
             AppError::Anyhow(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Serde(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        (code, self.to_string()).into_response()
+        let mut res = (code, self.to_string()).into_response();
+        let headers = res.headers_mut();
+        match self {
+            AppError::Unauthorized { challenge, .. } => {
+                if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+                    headers.insert(WWW_AUTHENTICATE, challenge);
+                }
+            }
+            AppError::RateLimited {
+                limit,
+                period,
+                retry_after,
+            } => {
+                headers.insert(RETRY_AFTER, retry_after.into());
+                headers.insert("ratelimit-limit", limit.into());
+                headers.insert("ratelimit-remaining", 0.into());
+                headers.insert("ratelimit-reset", retry_after.into());
+                if let Ok(policy) = HeaderValue::from_str(&format!("{};w={}", limit, period)) {
+                    headers.insert("ratelimit-policy", policy);
+                }
+            }
+            _ => {}
+        }
+        res
     }
 }
@@ -1,61 +1,228 @@
+mod access_log;
+mod admin;
+mod artifact;
+mod auth;
+mod cache;
+mod caches;
+mod compression;
 mod config;
+mod cors;
 mod engine;
 mod error;
+mod listener;
+mod metrics;
+mod ratelimit;
 mod router;
-use std::collections::HashMap;
+mod shutdown;
+mod split;
+mod telemetry;
+mod tenant;
+mod tls;
+mod utils;
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Instant};
 
-use anyhow::Result;
+use access_log::{AccessLog, RequestInfo};
+use admin::{InitialProject, Previews, Registry};
+
+use anyhow::{Context, Result, bail};
+use arc_swap::ArcSwap;
 use axum::{
     Router,
     body::Bytes,
-    extract::{Query, Request, State},
+    extract::{ConnectInfo, Query, Request, State},
+    http::{
+        HeaderValue, Method, Uri,
+        header::{ACCESS_CONTROL_REQUEST_METHOD, AGE},
+    },
     response::{IntoResponse, Response},
     routing::any,
 };
 use axum_extra::extract::Host;
-use config::ProjectRoute;
-use dashmap::DashMap;
+use cache::{ResponseCache, X_CACHE};
+use compression::decompress_body;
 use error::AppError;
 use indexmap::IndexMap;
-use router::AppRouter;
+use listener::{Listener, Peer};
+use metrics::{Metrics, RequestLabels};
+use opentelemetry::{
+    Context as TraceContext, KeyValue,
+    trace::{Span, TraceContextExt},
+};
+use ratelimit::Client;
 pub use router::SwappableAppRouter;
-use tokio::net::TcpListener;
-use tracing::info;
+use router::{AppRouter, rewrite_uri};
+use shutdown::RequestStats;
+use telemetry::Telemetry;
+pub use tenant::Tenants;
+use tls::CertResolver;
+pub use tls::TlsFiles;
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
 
-pub use config::ProjectConfig;
-pub use engine::{JsWorker, Req, Res};
+pub use access_log::X_REQUEST_ID;
+pub use admin::{
+    DeployRequest, DeploymentInfo, ProjectInfo, PromoteRequest, RollbackRequest, SplitRequest,
+    SplitTarget,
+};
+pub use artifact::{
+    ARTIFACT_MANIFEST, ARTIFACT_VERSION, Artifact, ArtifactFiles, ArtifactManifest, ArtifactSource,
+    BuildMetadata, FileDigest,
+};
+pub use config::{
+    AccessLogConfig, AccessLogFormat, AdminConfig, AuthConfig, BasicAuthConfig, BearerAuthConfig,
+    CacheConfig, CompressionConfig, CorsConfig, JwtAuthConfig, ListenAddr, LogDestination,
+    ManifestProject, ManifestSplit, MiddlewareConfig, ProjectConfig, ProjectRoute, RateLimitConfig,
+    RateLimitKey, RedirectConfig, RouteAction, RouteMethod, ServerConfig, ServerManifest,
+    SocketMode, SplitRule, TelemetryConfig,
+};
+pub use engine::{AuthInfo, JsWorker, JsonValue, Req, Res};
+pub use split::{DEPLOYMENT_COOKIE, Split, X_DEPLOYMENT};
 type ProjectRoutes = IndexMap<String, Vec<ProjectRoute>>;
 
 #[derive(Clone)]
 pub struct AppState {
-    // host -> router
-    routers: DashMap<String, SwappableAppRouter>,
+    // replaced as a whole when the admin api adds or removes a project
+    tenants: Arc<ArcSwap<Tenants>>,
+    stats: RequestStats,
+    metrics: Arc<Metrics>,
+    access_log: Option<Arc<AccessLog>>,
+    telemetry: Arc<Telemetry>,
 }
 
 #[derive(Clone)]
 pub struct TenentRouter {
     host: String,
     router: SwappableAppRouter,
+    tls: Option<TlsFiles>,
+    split: Option<Arc<Split>>,
 }
 
-pub async fn start_server(port: u16, routers: Vec<TenentRouter>) -> Result<()> {
-    let addr = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(addr).await?;
+// serve until SIGINT or SIGTERM, then wait up to the shutdown timeout for the in-flight requests
+pub async fn start_server(config: ServerConfig, routers: Vec<TenentRouter>) -> Result<()> {
+    let mut listeners = Listener::inherited()?;
+    for addr in &config.listen {
+        let listener = Listener::bind(addr, config.socket_mode)
+            .await
+            .with_context(|| format!("failed to listen on {}", addr))?;
+        listeners.push(listener);
+    }
+    if listeners.is_empty() {
+        bail!("no address to listen on");
+    }
 
-    info!("Server is running on {}", listener.local_addr()?);
+    // serve https if any tenant has a certificate
+    let certs = routers
+        .iter()
+        .filter_map(|r| r.tls.as_ref().map(|tls| (r.host.as_str(), tls)));
+    let resolver = CertResolver::try_new(certs)?.map(Arc::new);
+    let tls = match &resolver {
+        Some(resolver) => {
+            tokio::spawn(resolver.clone().watch());
+            Some(TlsAcceptor::from(resolver.clone().server_config()?))
+        }
+        None => None,
+    };
+    let scheme = if tls.is_some() { "https" } else { "http" };
 
-    let map = DashMap::new();
-    for TenentRouter { host, router } in routers {
-        map.insert(host, router);
-    }
+    let projects = group_projects(&routers);
+    let splits: Vec<_> = routers
+        .iter()
+        .filter_map(|r| Some((r.host.clone(), r.split.clone()?)))
+        .collect();
+    let routers = routers.into_iter().map(|r| (r.host, r.router));
+    let tenants = Tenants::try_new(routers, config.aliases.clone(), config.default_host.clone())?
+        .with_splits(splits)?;
 
-    let state = AppState::new(map);
+    let mut state = AppState::new(tenants);
+    if let Some(log) = &config.access_log {
+        state.access_log = Some(Arc::new(AccessLog::try_new(log)?));
+    }
+    if let Some(telemetry) = &config.telemetry {
+        state.telemetry = Arc::new(Telemetry::try_new(Some(telemetry))?);
+        info!("Exporting the spans to {}", telemetry.endpoint);
+    }
+    let stats = state.stats.clone();
     let app = Router::new()
         .route("/{*path}", any(handler))
-        .with_state(state);
+        .with_state(state.clone());
 
-    axum::serve(listener, app.into_make_service()).await?;
+    let started = Instant::now();
+    let signal = shutdown::listen(stats.clone());
+    let mut servers = JoinSet::new();
+    if let Some(admin) = config.admin {
+        // there is no way to tell who changed what without credentials
+        if admin.auth.is_public() {
+            bail!("the admin api requires auth");
+        }
+        // nor should the credentials cross the network in the clear
+        let admin_tls = match (admin.listen.is_local(), &tls) {
+            (true, _) => None,
+            (false, Some(tls)) => Some(tls.clone()),
+            (false, None) => bail!(
+                "the admin api on {} requires tls, listen on a loopback address instead",
+                admin.listen
+            ),
+        };
+        let listener = Listener::bind(&admin.listen, config.socket_mode)
+            .await
+            .with_context(|| format!("failed to listen on {}", admin.listen))?;
+        // the previews are served by the public listeners, the first tcp one is in the urls
+        let port = listeners.iter().find_map(Listener::port);
+        let previews = admin.preview_domain.map(|domain| Previews {
+            domain,
+            scheme,
+            port: port.filter(|port| !matches!((scheme, port), ("http", 80) | ("https", 443))),
+        });
+        let registry = Registry::try_new(
+            projects,
+            state.tenants.clone(),
+            config.aliases,
+            config.default_host,
+            previews,
+            admin.retention,
+        )?;
+        let app = admin::app(Arc::new(registry), admin.auth, state.metrics.clone());
+        let admin_scheme = if admin_tls.is_some() { "https" } else { "http" };
+        info!("Admin api is running on {}://{}", admin_scheme, listener);
+        let shutdown = shutdown::wait(signal.clone());
+        servers.spawn(listener::serve(listener, app, admin_tls, shutdown));
+    }
+    for listener in listeners {
+        info!("Server is running on {}://{}", scheme, listener);
+        let shutdown = shutdown::wait(signal.clone());
+        servers.spawn(listener::serve(
+            listener,
+            app.clone(),
+            tls.clone(),
+            shutdown,
+        ));
+    }
+    let serve = async {
+        while let Some(ret) = servers.join_next().await {
+            ret??;
+        }
+        Ok::<_, anyhow::Error>(())
+    };
+    tokio::select! {
+        ret = serve => ret?,
+        _ = shutdown::deadline(signal.clone(), config.shutdown_timeout) => {
+            warn!(
+                "Shutdown timed out after {:?}, dropping {} in-flight requests",
+                config.shutdown_timeout,
+                stats.inflight()
+            );
+        }
+    }
 
+    shutdown::unload(&state).await;
+    let telemetry = state.telemetry.clone();
+    tokio::task::spawn_blocking(move || telemetry.shutdown()).await?;
+    info!(
+        "Server stopped after {:?}, served {} requests",
+        started.elapsed(),
+        stats.served()
+    );
     Ok(())
 }
 
@@ -77,44 +244,231 @@ pub async fn start_server(port: u16, routers: Vec<TenentRouter>) -> Result<()> {
 // 将 Parts 改为 axum::http::request::Parts 以明确类型
 // 这些修改使得代码符合 Axum 0.8 的 Handler trait 要求，现在可以正常编译并且没有任何警告。
 
-// we only support JSON requests and return JSON responses
+// the failed requests are logged and recorded by the metrics as well
 async fn handler(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<Peer>,
     Host(host): Host,
     Query(query): Query<HashMap<String, String>>,
+    mut request: Request,
+) -> Response {
+    let started = Instant::now();
+    let request_id = access_log::request_id(request.headers());
+    let id = HeaderValue::from_str(&request_id).ok();
+    // the handlers see it among the headers as well
+    if let Some(id) = &id {
+        request.headers_mut().insert(X_REQUEST_ID, id.clone());
+    }
+    let logged = state
+        .access_log
+        .as_ref()
+        .map(|log| (log, RequestInfo::new(&request, peer, &host, &request_id)));
+    let mut labels = RequestLabels {
+        method: RequestLabels::method(request.method()),
+        ..Default::default()
+    };
+    let cx = state.telemetry.request(
+        request.headers(),
+        request.method(),
+        request.uri().path(),
+        &request_id,
+    );
+
+    let mut res = serve(&state, peer, host, query, request, &cx, &mut labels)
+        .await
+        .into_response();
+    if let Some(id) = id {
+        res.headers_mut().insert(X_REQUEST_ID, id);
+    }
+    let elapsed = started.elapsed();
+    if let Some((log, req)) = logged {
+        log.log(&req, &res, &labels, elapsed);
+    }
+    telemetry::finish(&cx, &labels, res.status());
+    state.metrics.record_request(labels, res.status(), elapsed);
+    res
+}
+
+// we only support JSON requests and return JSON responses, every stage is a span of `cx`
+async fn serve(
+    state: &AppState,
+    peer: Peer,
+    host: String,
+    query: HashMap<String, String>,
     request: Request,
-) -> Result<impl IntoResponse, AppError> {
-    let (parts, body) = request.into_parts();
+    cx: &TraceContext,
+    labels: &mut RequestLabels,
+) -> Result<Response, AppError> {
+    let _inflight = state.stats.track();
+    let telemetry = &state.telemetry;
+    let stage = telemetry.stage("read body", cx);
+    let (mut parts, body) = request.into_parts();
     let body = axum::body::to_bytes(body, usize::MAX).await.ok();
+    let body = match body {
+        Some(body) => Some(decompress_body(&mut parts.headers, body)?),
+        None => None,
+    };
+    drop(stage);
 
-    let router: AppRouter = get_router_by_host(host, state)?;
-    let matched = router.match_it(parts.method.clone(), parts.uri.path())?;
-    let handler = matched.value;
+    let stage = telemetry.stage("routing", cx);
 
-    info!(
-        "method:{}, path:{}, query:{:?}, body:{:?}",
-        parts.method,
-        parts.uri.path(),
-        query,
-        body
-    );
+    let tenants = state.tenants.load();
+    let tenant = tenants
+        .resolve(&host)
+        .ok_or_else(|| AppError::HostNotFound(host.clone()))?;
+    let choice = tenant.split.map(|split| split.choose(&parts.headers));
+    let router: AppRouter = choice
+        .as_ref()
+        .and_then(|choice| choice.router)
+        .unwrap_or(tenant.router)
+        .load();
+    labels.tenant = router.config.name.clone();
+    if let Some(res) = preflight(&router, &parts) {
+        return Ok(res);
+    }
+
+    let path = router.rewrite(&parts.method, parts.uri.path())?;
+    if path != parts.uri.path() {
+        debug!("rewrite {} -> {}", parts.uri.path(), path);
+        parts.uri = rewrite_uri(&parts.uri, &path)?;
+    }
+
+    let matched = router.match_it(parts.method.clone(), &path)?;
+    labels.route = matched.value.path.clone();
+    // see check_rate_limit for the order the limits are charged in
+    let client = Client {
+        peer,
+        headers: &parts.headers,
+        auth: None,
+    };
+    router.check_rate_limit(matched.value, &client)?;
+    let auth = match router.auth_for(matched.value) {
+        Some(auth) => auth.verify(&parts.headers).await,
+        None => Ok(None),
+    };
+    let client = Client {
+        auth: auth.as_ref().ok().and_then(|auth| auth.as_ref()),
+        ..client
+    };
+    router.check_subject_rate_limit(matched.value, &client)?;
+    let auth = auth?;
+    drop(stage);
 
-    let req = assemble_req(&matched, &parts, query, body)?;
+    // started as soon as the js returns, so the cache write is part of it
+    let mut encode = None;
+    let mut res = match &matched.value.action {
+        RouteAction::Handler(handler) => {
+            labels.handler = handler.clone();
+            let middleware = router.middleware_for(&path);
+            // a cached response would skip the middleware, e.g. an auth check
+            let cache = match middleware.is_empty() {
+                true => router.cache.as_ref().zip(ResponseCache::key(&host, &parts)),
+                false => None,
+            };
+            let cacheable = cache.is_some();
+            let cached = cache
+                .as_ref()
+                .and_then(|(cache, key)| cache.get(key, &parts.headers));
+            if let Some((res, age)) = cached {
+                let mut res = Response::from(res);
+                let headers = res.headers_mut();
+                headers.insert(X_CACHE, HeaderValue::from_static("HIT"));
+                headers.insert(AGE, age.as_secs().into());
+                res
+            } else {
+                // set by the wrapper, from the request or generated
+                let request_id = parts
+                    .headers
+                    .get(X_REQUEST_ID)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or_default();
+                let mut req = assemble_req(
+                    &matched,
+                    &parts,
+                    query,
+                    body,
+                    auth,
+                    tenant.subdomain,
+                    request_id,
+                )?;
+                let (js_router, handler) = (router.clone(), handler.clone());
+                let metrics = state.metrics.clone();
+                let (js_telemetry, js_cx) = (telemetry.clone(), cx.clone());
+                // js runs synchronously, keep it off the async workers so the runtime stays
+                // responsive (e.g. to the shutdown signal)
+                let res = tokio::task::spawn_blocking(move || {
+                    let _busy = metrics.js_busy();
+                    let started = Instant::now();
+                    let stage = js_telemetry.stage("worker startup", &js_cx);
+                    let worker = JsWorker::try_new(&js_router.code)?
+                        .with_caches(js_router.caches.clone())?;
+                    drop(stage);
 
-    info!("req: {:?}", req);
-    let worker = JsWorker::try_new(&router.code)?;
+                    let mut stage = js_telemetry.stage("js execution", &js_cx);
+                    stage.set_attribute(KeyValue::new("dino.handler", handler.clone()));
+                    let cx = js_cx.with_span(stage);
+                    req.trace_headers = js_telemetry.headers(&cx);
+                    let res = worker.run_with_middleware(&middleware, &handler, req);
+                    if let Err(e) = &res {
+                        telemetry::fail(&cx, e);
+                    }
+                    let tenant = &js_router.config.name;
+                    metrics.record_js(tenant, &handler, started.elapsed(), worker.memory_used());
+                    res
+                })
+                .await
+                .map_err(anyhow::Error::from)??;
 
-    let res = worker.run(handler, req)?;
+                encode = Some(telemetry.stage("encode response", cx));
 
-    info!("res: {:?}", res);
-    Ok(Response::from(res))
+                if let Some((cache, key)) = cache {
+                    cache.put(key, &parts.headers, &res);
+                }
+                let mut res = Response::from(res);
+                if cacheable {
+                    res.headers_mut()
+                        .insert(X_CACHE, HeaderValue::from_static("MISS"));
+                }
+                res
+            }
+        }
+        RouteAction::Redirect(redirect) => redirect.to_response(&matched.params, parts.uri.query()),
+        RouteAction::Rewrite(_) => unreachable!("rewrites are resolved by AppRouter::rewrite"),
+    };
+    let _stage = encode.unwrap_or_else(|| telemetry.stage("encode response", cx));
+    if let Some(cors) = router.cors_for(Some(matched.value)) {
+        cors.apply(&parts.headers, &mut res);
+    }
+    if let Some(compression) = &router.compression {
+        res = compression.apply(&parts.headers, res).await?;
+    }
+    if let Some(choice) = &choice {
+        choice.apply(&mut res);
+    }
+    Ok(res)
+}
+
+// answer cors preflight requests with the policy of the route being requested
+fn preflight(router: &AppRouter, parts: &axum::http::request::Parts) -> Option<Response> {
+    let method = parts.headers.get(ACCESS_CONTROL_REQUEST_METHOD)?;
+    let method = Method::from_bytes(method.as_bytes()).ok()?;
+    let route = router
+        .match_it(method, parts.uri.path())
+        .ok()
+        .map(|m| m.value);
+    router
+        .cors_for(route)?
+        .preflight(&parts.method, &parts.headers)
 }
 
 fn assemble_req(
-    matched: &matchit::Match<'_, '_, &str>,
+    matched: &matchit::Match<'_, '_, &ProjectRoute>,
     parts: &axum::http::request::Parts,
     query: HashMap<String, String>,
     body: Option<Bytes>,
+    auth: Option<AuthInfo>,
+    subdomain: Option<String>,
+    request_id: &str,
 ) -> Result<Req, AppError> {
     let params: HashMap<String, String> = matched
         .params
@@ -131,29 +485,69 @@ fn assemble_req(
 
     let req = Req::builder()
         .method(parts.method.to_string())
-        .url(parts.uri.to_string())
+        .url(path_and_query(&parts.uri))
+        .version(format!("{:?}", parts.version))
         .headers(headers)
         .query(query)
         .params(params)
         .body(body.unwrap_or_default())
+        .auth(auth)
+        .subdomain(subdomain)
+        .request_id(request_id)
         .build();
 
     Ok(req)
 }
 
-fn get_router_by_host(mut host: String, state: AppState) -> Result<AppRouter, AppError> {
-    let _ = host.split_off(host.find(':').unwrap_or(host.len()));
-    let router = state
-        .routers
-        .get(&host)
-        .ok_or(AppError::HostNotFound(host))?;
-    Ok(router.load())
+// http/2 requests carry the absolute uri, keep the url the same for all the protocols
+pub(crate) fn path_and_query(uri: &Uri) -> String {
+    uri.path_and_query()
+        .map(|p| p.to_string())
+        .unwrap_or_else(|| uri.path().to_string())
 }
 
 impl AppState {
-    pub fn new(routers: DashMap<String, SwappableAppRouter>) -> Self {
-        Self { routers }
+    pub fn new(tenants: Tenants) -> Self {
+        Self {
+            tenants: Arc::new(ArcSwap::from_pointee(tenants)),
+            stats: RequestStats::default(),
+            metrics: Default::default(),
+            access_log: None,
+            telemetry: Default::default(),
+        }
+    }
+}
+
+// a router serving several hosts is a single project
+fn group_projects(routers: &[TenentRouter]) -> Vec<InitialProject> {
+    let mut projects: Vec<InitialProject> = vec![];
+    for r in routers {
+        let project = projects
+            .iter_mut()
+            .find(|p| Arc::ptr_eq(&p.router.routers, &r.router.routers));
+        match project {
+            Some(project) => project.hosts.push(r.host.clone()),
+            None => {
+                let name = r.router.load().config.name.clone();
+                // different projects may share a name, keep them apart
+                let taken = |name: &str| projects.iter().any(|p| p.name == name);
+                let name = match taken(&name) {
+                    true => (2..)
+                        .map(|i| format!("{}-{}", name, i))
+                        .find(|n| !taken(n))
+                        .unwrap_or(name),
+                    false => name,
+                };
+                projects.push(InitialProject {
+                    name,
+                    hosts: vec![r.host.clone()],
+                    router: r.router.clone(),
+                    split: r.split.clone(),
+                });
+            }
+        }
     }
+    projects
 }
 
 impl TenentRouter {
@@ -161,6 +555,167 @@ impl TenentRouter {
         Self {
             host: host.into(),
             router,
+            tls: None,
+            split: None,
+        }
+    }
+
+    // serve a share of the host's traffic by other deployments, the router serves the rest
+    pub fn with_split(mut self, split: Arc<Split>) -> Self {
+        self.split = Some(split);
+        self
+    }
+
+    // serve the host over https with the certificate, reloaded when the files change
+    pub fn with_tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.tls = Some(TlsFiles {
+            cert: cert.into(),
+            key: key.into(),
+        });
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::connect_info::MockConnectInfo,
+        http::{Request, Version},
+    };
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn assemble_req_should_be_the_same_for_all_protocols() -> Result<()> {
+        let mut router = matchit::Router::new();
+        router.insert("/api/{id}", ())?;
+        let route: ProjectRoute = serde_yaml::from_str("{ method: GET, handler: hello }")?;
+
+        for (version, uri) in [
+            (Version::HTTP_11, "/api/1?a=b"),
+            (Version::HTTP_2, "https://localhost:8888/api/1?a=b"),
+        ] {
+            let (parts, _) = Request::get(uri).version(version).body(())?.into_parts();
+            let matched = router.at("/api/1")?;
+            let matched = matchit::Match {
+                value: &route,
+                params: matched.params,
+            };
+            let req = assemble_req(&matched, &parts, HashMap::new(), None, None, None, "id")?;
+            assert_eq!(req.url, "/api/1?a=b");
+            assert_eq!(req.request_id, "id");
+            assert_eq!(req.version, format!("{:?}", version));
+            assert_eq!(req.params["id"], "1");
+        }
+        Ok(())
+    }
+
+    // a.com served by the code
+    fn state(code: &str, config: &str) -> Result<AppState> {
+        let router = SwappableAppRouter::try_new(code, serde_yaml::from_str(config)?)?;
+        let tenants = Tenants::try_new([("a.com".to_string(), router)], HashMap::new(), None)?;
+        Ok(AppState::new(tenants))
+    }
+
+    fn app(state: AppState) -> Router {
+        Router::new()
+            .route("/{*path}", any(handler))
+            .with_state(state)
+            .layer(MockConnectInfo(Peer::Tcp(([127, 0, 0, 1], 4000).into())))
+    }
+
+    #[tokio::test]
+    async fn cached_responses_should_not_skip_middleware() -> Result<()> {
+        let code = r#"(function(){
+            async function hello(req){return{status:200,headers:{"cache-control":"max-age=60"},body:"hi"};}
+            async function guard(req, next){
+              if (req.headers["x-allow"]) return next();
+              return {status:403,headers:{},body:"denied"};
+            }
+            return{hello,guard};
+        })();"#;
+        let config = r#"
+name: app
+cache: {}
+middleware:
+  - handler: guard
+    prefix: /private
+routes:
+  /{*path}: [{ method: ANY, handler: hello }]
+"#;
+        let app = app(state(code, config)?);
+        let get = |path: &str, allow: bool| {
+            let mut req = Request::get(path).header("host", "a.com");
+            if allow {
+                req = req.header("x-allow", "1");
+            }
+            req.body(Body::empty()).unwrap()
+        };
+
+        let res = app.clone().oneshot(get("/private", true)).await?;
+        assert_eq!(res.status(), 200);
+        assert!(res.headers().get(X_CACHE).is_none());
+        let res = app.clone().oneshot(get("/private", false)).await?;
+        assert_eq!(res.status(), 403);
+
+        // without middleware the responses are cached, only the cacheable requests say so
+        let res = app.clone().oneshot(get("/public", false)).await?;
+        assert_eq!(res.headers()[X_CACHE], "MISS");
+        let res = app.clone().oneshot(get("/public", false)).await?;
+        assert_eq!(res.headers()[X_CACHE], "HIT");
+        let req = Request::post("/public")
+            .header("host", "a.com")
+            .body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert!(res.headers().get(X_CACHE).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn handler_should_trace_every_stage() -> Result<()> {
+        let code = "(function(){async function hello(req){return{status:200,headers:{},body:req.trace_headers.traceparent};}return{hello};})();";
+        let config = "{ name: app, routes: { '/api/{id}': [{ method: GET, handler: hello }] } }";
+        let (telemetry, exporter) = Telemetry::in_memory();
+        let mut state = state(code, config)?;
+        state.telemetry = Arc::new(telemetry);
+        let app = app(state);
+
+        let trace_id = "4bf92f3577b34da6a3ce929d0e0e4736";
+        let req = Request::get("/api/1")
+            .header("host", "a.com")
+            .header(
+                "traceparent",
+                format!("00-{}-00f067aa0ba902b7-01", trace_id),
+            )
+            .body(Body::empty())?;
+        // the exporter is cleared once the app is dropped
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), 200);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await?;
+
+        let spans = exporter.get_finished_spans()?;
+        let names: Vec<_> = spans.iter().map(|s| s.name.as_ref()).collect();
+        assert_eq!(
+            names,
+            [
+                "read body",
+                "routing",
+                "worker startup",
+                "js execution",
+                "encode response",
+                "GET /api/{id}"
+            ]
+        );
+        let request = &spans[5].span_context;
+        assert_eq!(request.trace_id().to_string(), trace_id);
+        for span in &spans[..5] {
+            assert_eq!(span.parent_span_id, request.span_id());
         }
+        // the handler continues the trace from the span running it
+        let js = spans[3].span_context.span_id();
+        assert_eq!(body, format!("00-{}-{}-01", trace_id, js));
+        Ok(())
     }
 }
@@ -0,0 +1,357 @@
+#[cfg(unix)]
+use std::{
+    env, fs,
+    os::{
+        fd::{FromRawFd, IntoRawFd, RawFd},
+        unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use anyhow::{Result, bail};
+use axum::{Router, extract::ConnectInfo, http::Request};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{
+        conn::auto,
+        graceful::{GracefulShutdown, Watcher},
+    },
+    service::TowerToHyperService,
+};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::warn;
+
+use crate::config::{ListenAddr, SocketMode};
+
+// the first fd passed by systemd socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: RawFd = 3;
+// a client that never finishes the handshake would hold the connection forever
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    // the path is removed on shutdown unless the socket was inherited
+    #[cfg(unix)]
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+// the other end of a connection, what the handlers get as `ConnectInfo`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Peer {
+    Tcp(SocketAddr),
+    // local and without an ip, e.g. a reverse proxy on the host, told apart by its uid
+    Unix(Option<u32>),
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr, mode: Option<SocketMode>) -> Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => bind_unix(path, mode),
+            #[cfg(not(unix))]
+            ListenAddr::Unix(_) => {
+                let _ = mode;
+                bail!("unix sockets are not supported on this platform")
+            }
+        }
+    }
+
+    // listeners passed by systemd socket activation, see sd_listen_fds(3)
+    #[cfg(unix)]
+    pub fn inherited() -> Result<Vec<Self>> {
+        let Ok(fds) = env::var("LISTEN_FDS") else {
+            return Ok(vec![]);
+        };
+        let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+        if pid != Some(std::process::id()) {
+            return Ok(vec![]);
+        }
+        let fds: RawFd = fds.parse()?;
+        (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + fds)
+            .map(Self::from_fd)
+            .collect()
+    }
+
+    #[cfg(not(unix))]
+    pub fn inherited() -> Result<Vec<Self>> {
+        Ok(vec![])
+    }
+
+    // None for unix sockets
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().ok().map(|addr| addr.port()),
+            #[cfg(unix)]
+            Listener::Unix(..) => None,
+        }
+    }
+
+    #[cfg(unix)]
+    fn from_fd(fd: RawFd) -> Result<Self> {
+        // SAFETY: the fds from LISTEN_FDS are open listening sockets handed over to us
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+        // only succeeds for unix sockets, anything else is a tcp socket
+        if unix.local_addr().is_ok() {
+            unix.set_nonblocking(true)?;
+            return Ok(Listener::Unix(UnixListener::from_std(unix)?, None));
+        }
+        // SAFETY: the fd is owned by `unix` which gives it up
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(unix.into_raw_fd()) };
+        tcp.set_nonblocking(true)?;
+        Ok(Listener::Tcp(TcpListener::from_std(tcp)?))
+    }
+}
+
+// the socket is bound in a private dir and moved into place once its mode is set, so it's
+// never reachable with the default permissions
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<SocketMode>) -> Result<Listener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        // a socket left behind by a previous run is replaced, one still in use is not
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("{} is in use by another process", path.display());
+        }
+    }
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+        bail!("invalid socket path: {}", path.display());
+    };
+    let private = dir.join(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let bound = private.join(name);
+    let ret = UnixListener::bind(&bound)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            if let Some(SocketMode(mode)) = mode {
+                fs::set_permissions(&bound, fs::Permissions::from_mode(mode))?;
+            }
+            fs::rename(&bound, path)?;
+            Ok(listener)
+        });
+    let _ = fs::remove_file(&bound);
+    let _ = fs::remove_dir(&private);
+    Ok(Listener::Unix(ret?, Some(path.to_path_buf())))
+}
+
+// the ip, or `unix:<uid>` which is never mistaken for one
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Peer::Tcp(addr) => write!(f, "{}", addr.ip()),
+            Peer::Unix(Some(uid)) => write!(f, "unix:{}", uid),
+            Peer::Unix(None) => write!(f, "unix"),
+        }
+    }
+}
+
+impl fmt::Display for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            // bound elsewhere and moved, the kernel still reports the old path
+            #[cfg(unix)]
+            Listener::Unix(_, Some(path)) => write!(f, "unix:{}", path.display()),
+            #[cfg(unix)]
+            Listener::Unix(listener, None) => {
+                let addr = listener.local_addr().ok();
+                match addr.as_ref().and_then(|a| a.as_pathname()) {
+                    Some(path) => write!(f, "unix:{}", path.display()),
+                    None => write!(f, "unix"),
+                }
+            }
+        }
+    }
+}
+
+// accept connections and serve them with the app, until the shutdown resolves and the
+// open connections are drained
+pub(crate) async fn serve(
+    listener: Listener,
+    app: Router,
+    tls: Option<TlsAcceptor>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<()> {
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(shutdown);
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept(&listener) => accepted,
+            _ = &mut shutdown => break,
+        };
+        match accepted {
+            Ok(Accepted::Tcp(stream, addr)) => {
+                spawn_conn(stream, Peer::Tcp(addr), &app, &tls, graceful.watcher())
+            }
+            #[cfg(unix)]
+            Ok(Accepted::Unix(stream)) => {
+                let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+                spawn_conn(stream, Peer::Unix(uid), &app, &tls, graceful.watcher())
+            }
+            Err(e) => {
+                // e.g. too many open files, back off instead of spinning
+                warn!("failed to accept connection: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    let path = match &listener {
+        Listener::Unix(_, path) => path.clone(),
+        Listener::Tcp(_) => None,
+    };
+    drop(listener);
+    graceful.shutdown().await;
+    #[cfg(unix)]
+    if let Some(path) = path {
+        let _ = fs::remove_file(path);
+    }
+    Ok(())
+}
+
+enum Accepted {
+    Tcp(tokio::net::TcpStream, SocketAddr),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+async fn accept(listener: &Listener) -> std::io::Result<Accepted> {
+    match listener {
+        Listener::Tcp(listener) => {
+            let (stream, addr) = listener.accept().await?;
+            Ok(Accepted::Tcp(stream, addr))
+        }
+        #[cfg(unix)]
+        Listener::Unix(listener, _) => {
+            let (stream, _) = listener.accept().await?;
+            Ok(Accepted::Unix(stream))
+        }
+    }
+}
+
+fn spawn_conn<S>(stream: S, peer: Peer, app: &Router, tls: &Option<TlsAcceptor>, watcher: Watcher)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let app = app.clone();
+    let tls = tls.clone();
+    tokio::spawn(async move {
+        let ret = match tls {
+            Some(acceptor) => {
+                let handshake = acceptor.accept(stream);
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+                    Ok(Ok(stream)) => serve_conn(stream, peer, app, watcher).await,
+                    Ok(Err(e)) => {
+                        warn!("tls handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                    Err(_) => {
+                        warn!("tls handshake with {} timed out", peer);
+                        return;
+                    }
+                }
+            }
+            None => serve_conn(stream, peer, app, watcher).await,
+        };
+        if let Err(e) = ret {
+            warn!("failed to serve connection from {}: {}", peer, e);
+        }
+    });
+}
+
+// http/1.1 or http/2 (negotiated by ALPN or prior knowledge) on a single connection
+async fn serve_conn<S>(
+    stream: S,
+    peer: Peer,
+    app: Router,
+    watcher: Watcher,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = app.map_request(move |mut req: Request<Incoming>| {
+        req.extensions_mut().insert(ConnectInfo(peer));
+        req
+    });
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection(TokioIo::new(stream), TowerToHyperService::new(service));
+    watcher.watch(conn).await
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+mod tests {
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unix_socket_should_serve() -> Result<()> {
+        let path = env::temp_dir().join(format!("dino-{}.sock", std::process::id()));
+        let addr = ListenAddr::Unix(path.clone());
+        let listener = Listener::bind(&addr, Some(SocketMode(0o600))).await?;
+        assert_eq!(listener.to_string(), addr.to_string());
+        let mode = fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // a live socket is never replaced
+        let err = Listener::bind(&addr, None).await.err().unwrap();
+        assert!(err.to_string().contains("in use"));
+
+        let app = Router::new().route(
+            "/",
+            get(|ConnectInfo(peer): ConnectInfo<Peer>| async move { peer.to_string() }),
+        );
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app, None, async {
+            let _ = rx.await;
+        }));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await?;
+        assert!(res.starts_with("HTTP/1.1 200 OK"));
+        let uid = fs::metadata(&path).map(|m| std::os::unix::fs::MetadataExt::uid(&m))?;
+        assert!(res.ends_with(&format!("unix:{}", uid)));
+
+        tx.send(()).unwrap();
+        server.await??;
+        assert!(!path.exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stale_unix_socket_should_be_replaced() -> Result<()> {
+        let path = env::temp_dir().join(format!("dino-stale-{}.sock", std::process::id()));
+        // bound and dropped without removing the path, like a crashed run
+        drop(std::os::unix::net::UnixListener::bind(&path)?);
+        let listener = Listener::bind(&ListenAddr::Unix(path.clone()), None).await?;
+        assert!(tokio::net::UnixStream::connect(&path).await.is_ok());
+        drop(listener);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
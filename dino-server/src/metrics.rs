@@ -0,0 +1,411 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicI64, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use axum::http::{Method, StatusCode};
+use dashmap::DashMap;
+
+use crate::Tenants;
+
+// upper bounds in seconds, both the requests and the js runs are in this range
+const BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// the methods worth a label of their own, the others are `OTHER`
+const METHODS: [Method; 9] = [
+    Method::GET,
+    Method::HEAD,
+    Method::POST,
+    Method::PUT,
+    Method::DELETE,
+    Method::CONNECT,
+    Method::OPTIONS,
+    Method::TRACE,
+    Method::PATCH,
+];
+
+// served at `/metrics` of the admin api, in the prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    requests: DashMap<(RequestLabels, u16), u64>,
+    latency: DashMap<RequestLabels, Histogram>,
+    // (tenant, handler) -> time spent in the js runtime
+    js_time: DashMap<(String, String), Histogram>,
+    // tenant -> bytes used by the latest js runtime
+    js_memory: DashMap<String, u64>,
+    // js runs in flight on the blocking threads
+    js_busy: AtomicI64,
+}
+
+// what is known of a request once it's served, empty if it failed before getting there
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash)]
+pub struct RequestLabels {
+    // the project name
+    pub tenant: String,
+    // the matched route, e.g. `/api/{id}`
+    pub route: String,
+    pub handler: String,
+    pub method: String,
+}
+
+// hot reloads of a router, see SwappableAppRouter::reload
+#[derive(Debug, Default)]
+pub struct ReloadStats {
+    pub total: AtomicU64,
+    pub failed: AtomicU64,
+}
+
+#[derive(Default)]
+struct Histogram {
+    // not cumulative, the last one is +Inf
+    counts: [u64; BUCKETS.len() + 1],
+    sum: f64,
+}
+
+// a js run in flight until dropped
+pub struct JsBusy<'a>(&'a AtomicI64);
+
+impl RequestLabels {
+    // a client can send any method, the label stays bounded
+    pub fn method(method: &Method) -> String {
+        match METHODS.contains(method) {
+            true => method.to_string(),
+            false => "OTHER".to_string(),
+        }
+    }
+}
+
+impl Metrics {
+    pub fn record_request(&self, labels: RequestLabels, status: StatusCode, elapsed: Duration) {
+        // e.g. an unknown host, one series per method and status whatever the request was
+        let labels = match labels.tenant.is_empty() {
+            true => RequestLabels {
+                method: labels.method,
+                ..Default::default()
+            },
+            false => labels,
+        };
+        *self
+            .requests
+            .entry((labels.clone(), status.as_u16()))
+            .or_default() += 1;
+        self.latency
+            .entry(labels)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_js(&self, tenant: &str, handler: &str, elapsed: Duration, memory: u64) {
+        let key = (tenant.to_string(), handler.to_string());
+        self.js_time
+            .entry(key)
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+        self.js_memory.insert(tenant.to_string(), memory);
+    }
+
+    pub fn js_busy(&self) -> JsBusy<'_> {
+        self.js_busy.fetch_add(1, Ordering::Relaxed);
+        JsBusy(&self.js_busy)
+    }
+
+    // the bundles and reloads are read from the routers being served
+    pub fn render(&self, tenants: &Tenants) -> String {
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "dino_http_requests_total",
+            "counter",
+            "Requests served by tenant, route, handler, method and status.",
+        );
+        let mut lines: Vec<_> = self
+            .requests
+            .iter()
+            .map(|entry| {
+                let ((labels, status), count) = entry.pair();
+                let status = status.to_string();
+                let labels = format_labels(&[
+                    ("tenant", &labels.tenant),
+                    ("route", &labels.route),
+                    ("handler", &labels.handler),
+                    ("method", &labels.method),
+                    ("status", &status),
+                ]);
+                format!("dino_http_requests_total{} {}\n", labels, count)
+            })
+            .collect();
+        lines.sort();
+        out.extend(lines);
+
+        header(
+            &mut out,
+            "dino_http_request_duration_seconds",
+            "histogram",
+            "Time to serve a request by tenant, route, handler and method.",
+        );
+        let mut lines: Vec<_> = self
+            .latency
+            .iter()
+            .map(|entry| {
+                let (labels, histogram) = entry.pair();
+                let labels = [
+                    ("tenant", labels.tenant.as_str()),
+                    ("route", &labels.route),
+                    ("handler", &labels.handler),
+                    ("method", &labels.method),
+                ];
+                histogram.render("dino_http_request_duration_seconds", &labels)
+            })
+            .collect();
+        lines.sort();
+        out.extend(lines);
+
+        header(
+            &mut out,
+            "dino_js_execution_seconds",
+            "histogram",
+            "Time spent in the js runtime by tenant and handler.",
+        );
+        let mut lines: Vec<_> = self
+            .js_time
+            .iter()
+            .map(|entry| {
+                let ((tenant, handler), histogram) = entry.pair();
+                let labels = [("tenant", tenant.as_str()), ("handler", handler)];
+                histogram.render("dino_js_execution_seconds", &labels)
+            })
+            .collect();
+        lines.sort();
+        out.extend(lines);
+
+        header(
+            &mut out,
+            "dino_js_memory_bytes",
+            "gauge",
+            "Memory used by the latest js runtime of the tenant.",
+        );
+        let mut lines: Vec<_> = self
+            .js_memory
+            .iter()
+            .map(|entry| {
+                let labels = format_labels(&[("tenant", entry.key())]);
+                format!("dino_js_memory_bytes{} {}\n", labels, entry.value())
+            })
+            .collect();
+        lines.sort();
+        out.extend(lines);
+
+        header(
+            &mut out,
+            "dino_js_workers_busy",
+            "gauge",
+            "Js runs in flight on the blocking threads.",
+        );
+        let busy = self.js_busy.load(Ordering::Relaxed);
+        let _ = writeln!(out, "dino_js_workers_busy {}", busy);
+
+        // (host, tenant, [bundle size, reloads, failed reloads])
+        let routers: Vec<_> = tenants
+            .routers()
+            .into_iter()
+            .map(|(host, router)| {
+                let current = router.load();
+                let values = [
+                    current.code.len() as u64,
+                    router.reloads.total.load(Ordering::Relaxed),
+                    router.reloads.failed.load(Ordering::Relaxed),
+                ];
+                (host, current.config.name.clone(), values)
+            })
+            .collect();
+        let series = [
+            (
+                "dino_bundle_bytes",
+                "gauge",
+                "Size of the bundle served by the router of the host.",
+            ),
+            (
+                "dino_reloads_total",
+                "counter",
+                "Hot reloads of the router of the host.",
+            ),
+            (
+                "dino_reload_failures_total",
+                "counter",
+                "Hot reloads of the router of the host that kept the old code.",
+            ),
+        ];
+        for (i, (name, kind, help)) in series.into_iter().enumerate() {
+            header(&mut out, name, kind, help);
+            let mut lines: Vec<_> = routers
+                .iter()
+                .map(|(host, tenant, values)| {
+                    let labels = format_labels(&[("tenant", tenant), ("host", host)]);
+                    format!("{}{} {}\n", name, labels, values[i])
+                })
+                .collect();
+            lines.sort();
+            out.extend(lines);
+        }
+        out
+    }
+}
+
+impl ReloadStats {
+    pub fn record(&self, ok: bool) {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let i = BUCKETS
+            .iter()
+            .position(|le| value <= *le)
+            .unwrap_or(BUCKETS.len());
+        self.counts[i] += 1;
+        self.sum += value;
+    }
+
+    fn render(&self, name: &str, labels: &[(&str, &str)]) -> String {
+        let mut out = String::new();
+        let mut cumulative = 0;
+        let bounds = BUCKETS.iter().map(|le| le.to_string());
+        for (le, count) in bounds.chain(["+Inf".to_string()]).zip(self.counts) {
+            cumulative += count;
+            let mut labels = labels.to_vec();
+            labels.push(("le", &le));
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                format_labels(&labels),
+                cumulative
+            );
+        }
+        let labels = format_labels(labels);
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, cumulative);
+        out
+    }
+}
+
+impl Drop for JsBusy<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::{ProjectConfig, SwappableAppRouter};
+
+    #[test]
+    fn metrics_should_render_prometheus_text() -> Result<()> {
+        let config: ProjectConfig = serde_yaml::from_str("{ name: app, routes: {} }")?;
+        let router = SwappableAppRouter::try_new("1234", config)?;
+        router.reloads.record(true);
+        router.reloads.record(false);
+        let tenants = Tenants::try_new([("a.com".to_string(), router)], HashMap::new(), None)?;
+
+        let metrics = Metrics::default();
+        let labels = RequestLabels {
+            tenant: "app".to_string(),
+            route: "/api/{id}".to_string(),
+            handler: "hello".to_string(),
+            method: "GET".to_string(),
+        };
+        for ms in [3, 30, 20_000] {
+            let elapsed = Duration::from_millis(ms);
+            metrics.record_request(labels.clone(), StatusCode::OK, elapsed);
+        }
+        metrics.record_request(labels, StatusCode::NOT_FOUND, Duration::ZERO);
+        let unresolved = RequestLabels {
+            route: "/made/up".to_string(),
+            method: RequestLabels::method(&Method::from_bytes(b"MADEUP")?),
+            ..Default::default()
+        };
+        metrics.record_request(unresolved, StatusCode::NOT_FOUND, Duration::ZERO);
+        metrics.record_js("app", "hello", Duration::from_millis(2), 4096);
+        let busy = metrics.js_busy();
+
+        let text = metrics.render(&tenants);
+        let labels = r#"tenant="app",route="/api/{id}",handler="hello",method="GET""#;
+        for line in [
+            format!(r#"dino_http_requests_total{{{},status="200"}} 3"#, labels),
+            format!(r#"dino_http_requests_total{{{},status="404"}} 1"#, labels),
+            format!(
+                r#"dino_http_request_duration_seconds_bucket{{{},le="0.005"}} 2"#,
+                labels
+            ),
+            format!(
+                r#"dino_http_request_duration_seconds_bucket{{{},le="10"}} 3"#,
+                labels
+            ),
+            format!(
+                r#"dino_http_request_duration_seconds_bucket{{{},le="+Inf"}} 4"#,
+                labels
+            ),
+            format!(
+                r#"dino_http_request_duration_seconds_count{{{}}} 4"#,
+                labels
+            ),
+            r#"dino_js_execution_seconds_count{tenant="app",handler="hello"} 1"#.to_string(),
+            r#"dino_js_memory_bytes{tenant="app"} 4096"#.to_string(),
+            r#"dino_http_requests_total{tenant="",route="",handler="",method="OTHER",status="404"} 1"#
+                .to_string(),
+            "dino_js_workers_busy 1".to_string(),
+            r#"dino_bundle_bytes{tenant="app",host="a.com"} 4"#.to_string(),
+            r#"dino_reloads_total{tenant="app",host="a.com"} 2"#.to_string(),
+            r#"dino_reload_failures_total{tenant="app",host="a.com"} 1"#.to_string(),
+        ] {
+            assert!(text.lines().any(|l| l == line), "{} not in\n{}", line, text);
+        }
+        drop(busy);
+        assert!(
+            metrics
+                .render(&tenants)
+                .contains("dino_js_workers_busy 0\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn labels_should_be_escaped() {
+        assert_eq!(
+            format_labels(&[("route", "/a\"b\\"), ("x", "1\n")]),
+            r#"{route="/a\"b\\",x="1\n"}"#
+        );
+    }
+}
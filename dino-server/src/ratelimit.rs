@@ -0,0 +1,213 @@
+use std::time::Instant;
+
+use axum::http::HeaderMap;
+use dashmap::DashMap;
+
+use crate::{
+    config::{RateLimitConfig, RateLimitKey},
+    engine::AuthInfo,
+    error::AppError,
+    listener::Peer,
+};
+
+// drop idle buckets once there are this many
+const MAX_BUCKETS: usize = 10_000;
+
+#[derive(Default)]
+pub struct RateLimiter {
+    // `<scope>|<client key>` -> bucket
+    buckets: DashMap<String, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    // seconds to refill from empty, a bucket idle for that long is as good as a new one
+    refill_secs: f64,
+}
+
+// who is making the request, used to pick the bucket
+pub struct Client<'a> {
+    pub peer: Peer,
+    pub headers: &'a HeaderMap,
+    pub auth: Option<&'a AuthInfo>,
+}
+
+impl RateLimitConfig {
+    fn capacity(&self) -> f64 {
+        self.burst.unwrap_or(self.requests) as f64
+    }
+
+    fn refill_rate(&self) -> f64 {
+        self.requests as f64 / self.period as f64
+    }
+
+    fn client_key(&self, client: &Client) -> String {
+        match &self.key {
+            RateLimitKey::Ip => client.peer.to_string(),
+            // never mistaken for an ip, whatever the subject is
+            RateLimitKey::Subject => client
+                .auth
+                .and_then(|auth| auth.subject.as_ref())
+                .map(|subject| format!("sub:{}", subject))
+                .unwrap_or_else(|| client.peer.to_string()),
+            RateLimitKey::Header(name) => client
+                .headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string())
+                .unwrap_or_else(|| client.peer.to_string()),
+        }
+    }
+}
+
+impl RateLimiter {
+    // take a token from the bucket of the client in the given scope (project or route)
+    pub fn check(
+        &self,
+        scope: &str,
+        config: &RateLimitConfig,
+        client: &Client,
+    ) -> Result<(), AppError> {
+        self.check_at(scope, config, client, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        scope: &str,
+        config: &RateLimitConfig,
+        client: &Client,
+        now: Instant,
+    ) -> Result<(), AppError> {
+        if self.buckets.len() > MAX_BUCKETS {
+            self.buckets
+                .retain(|_, b| now.duration_since(b.updated).as_secs_f64() < b.refill_secs);
+        }
+
+        let key = format!("{}|{}", scope, config.client_key(client));
+        let capacity = config.capacity();
+        let rate = config.refill_rate();
+        let mut bucket = self.buckets.entry(key).or_insert_with(|| Bucket {
+            tokens: capacity,
+            updated: now,
+            refill_secs: capacity / rate,
+        });
+        // the config may have changed since, e.g. on a reload
+        bucket.refill_secs = capacity / rate;
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(AppError::RateLimited {
+            limit: config.requests,
+            period: config.period,
+            // seconds until the next token is available
+            retry_after: ((1.0 - bucket.tokens) / rate).ceil() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn token_bucket_should_refill() {
+        let limiter = RateLimiter::default();
+        let config = RateLimitConfig {
+            requests: 2,
+            period: 10,
+            burst: None,
+            key: RateLimitKey::Ip,
+        };
+        let headers = HeaderMap::new();
+        let client = |ip: &str| Client {
+            peer: Peer::Tcp((ip.parse::<std::net::IpAddr>().unwrap(), 4000).into()),
+            headers: &headers,
+            auth: None,
+        };
+        let now = Instant::now();
+
+        assert!(
+            limiter
+                .check_at("*", &config, &client("1.1.1.1"), now)
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .check_at("*", &config, &client("1.1.1.1"), now)
+                .is_ok()
+        );
+        let err = limiter
+            .check_at("*", &config, &client("1.1.1.1"), now)
+            .unwrap_err();
+        assert!(matches!(err, AppError::RateLimited { retry_after: 5, .. }));
+
+        // other clients and scopes have their own buckets
+        assert!(
+            limiter
+                .check_at("*", &config, &client("2.2.2.2"), now)
+                .is_ok()
+        );
+        let local = Client {
+            peer: Peer::Unix(Some(1000)),
+            ..client("1.1.1.1")
+        };
+        assert!(limiter.check_at("*", &config, &local, now).is_ok());
+        assert!(
+            limiter
+                .check_at("GET /a", &config, &client("1.1.1.1"), now)
+                .is_ok()
+        );
+
+        let later = now + Duration::from_secs(5);
+        assert!(
+            limiter
+                .check_at("*", &config, &client("1.1.1.1"), later)
+                .is_ok()
+        );
+        assert!(
+            limiter
+                .check_at("*", &config, &client("1.1.1.1"), later)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn idle_buckets_should_be_pruned_by_their_own_period() {
+        let limiter = RateLimiter::default();
+        let config = |period| RateLimitConfig {
+            requests: 1,
+            period,
+            burst: None,
+            key: RateLimitKey::Ip,
+        };
+        let (short, long) = (config(1), config(3600));
+        let headers = HeaderMap::new();
+        let client = Client {
+            peer: Peer::Tcp(([1, 1, 1, 1], 4000).into()),
+            headers: &headers,
+            auth: None,
+        };
+        let now = Instant::now();
+        assert!(limiter.check_at("long", &long, &client, now).is_ok());
+        for i in 0..MAX_BUCKETS {
+            let scope = format!("short {}", i);
+            assert!(limiter.check_at(&scope, &short, &client, now).is_ok());
+        }
+
+        // the short buckets are full again, the long one is still empty
+        let later = now + Duration::from_secs(60);
+        assert!(limiter.check_at("*", &short, &client, later).is_ok());
+        assert_eq!(limiter.buckets.len(), 2);
+        assert!(limiter.check_at("long", &long, &client, later).is_err());
+    }
+}
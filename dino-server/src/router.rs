@@ -1,19 +1,54 @@
-use anyhow::Result;
+use anyhow::{Result, bail};
 use arc_swap::ArcSwap;
-use axum::http::Method;
-use matchit::{Match, Router};
-use std::{ops::Deref, sync::Arc};
+use axum::{
+    body::Body,
+    http::{Method, StatusCode, Uri, header::LOCATION},
+    response::Response,
+};
+use matchit::{InsertError, Match, Params, Router};
+use std::{collections::HashMap, ops::Deref, path::PathBuf, sync::Arc};
 
-use crate::{ProjectRoutes, error::AppError};
+use crate::{
+    ProjectConfig, ProjectRoutes,
+    cache::ResponseCache,
+    caches::CacheStorage,
+    config::{
+        AuthConfig, CompressionConfig, CorsConfig, MiddlewareConfig, ProjectRoute, RateLimitConfig,
+        RateLimitKey, RedirectConfig, RouteAction, RouteMethod,
+    },
+    error::AppError,
+    metrics::ReloadStats,
+    ratelimit::{Client, RateLimiter},
+};
+
+// max number of internal rewrites followed for a single request
+const MAX_REWRITES: usize = 8;
+// 304 is a redirection status as well, but not one pointing elsewhere
+const REDIRECT_STATUSES: [u16; 5] = [301, 302, 303, 307, 308];
 
 #[derive(Clone)]
 pub struct SwappableAppRouter {
     pub routers: Arc<ArcSwap<AppRouterInner>>,
+    pub reloads: Arc<ReloadStats>,
 }
 
 pub struct AppRouterInner {
     pub code: String,
+    // kept to redeploy the code, e.g. on a rollback
+    pub config: ProjectConfig,
     pub router: Router<MethodRoute>,
+    pub cors: Option<CorsConfig>,
+    pub middleware: Vec<MiddlewareConfig>,
+    pub auth: Option<AuthConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    // token buckets of both the project and route level limits, kept across swaps so a
+    // reload doesn't refill them
+    pub limiter: Arc<RateLimiter>,
+    pub compression: Option<CompressionConfig>,
+    // dropped together with the router, so a swap purges it
+    pub cache: Option<ResponseCache>,
+    // the `caches` of the js code, kept across swaps
+    pub caches: Arc<CacheStorage>,
 }
 
 #[derive(Clone)]
@@ -21,45 +56,40 @@ pub struct AppRouter(Arc<AppRouterInner>);
 
 #[derive(Debug, Default, Clone)]
 pub struct MethodRoute {
-    get: Option<String>, // handler name  in js code
-    post: Option<String>,
-    put: Option<String>,
-    delete: Option<String>,
-    patch: Option<String>,
-    options: Option<String>,
-    head: Option<String>,
-    connect: Option<String>,
-    trace: Option<String>,
+    methods: HashMap<Method, ProjectRoute>, // route with the handler name in js code
+    any: Option<ProjectRoute>,
 }
 
 impl SwappableAppRouter {
-    pub fn try_new(code: impl Into<String>, routes: ProjectRoutes) -> Result<Self> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router)?;
+    pub fn try_new(code: impl Into<String>, config: ProjectConfig) -> Result<Self> {
+        let router = Self::get_router(&config.routes)?;
+        let inner =
+            AppRouterInner::new(code, router, config, Default::default(), Default::default())?;
         Ok(Self {
             routers: Arc::new(ArcSwap::from_pointee(inner)),
+            reloads: Default::default(),
         })
     }
 
-    fn get_router(routes: ProjectRoutes) -> Result<Router<MethodRoute>> {
+    fn get_router(routes: &ProjectRoutes) -> Result<Router<MethodRoute>> {
         let mut router = Router::new();
         for (path, methods) in routes {
             let mut method_route = MethodRoute::default();
             for method in methods {
-                match method.method {
-                    Method::GET => method_route.get = Some(method.handler),
-                    Method::POST => method_route.post = Some(method.handler),
-                    Method::PUT => method_route.put = Some(method.handler),
-                    Method::DELETE => method_route.delete = Some(method.handler),
-                    Method::PATCH => method_route.patch = Some(method.handler),
-                    Method::OPTIONS => method_route.options = Some(method.handler),
-                    Method::HEAD => method_route.head = Some(method.handler),
-                    Method::CONNECT => method_route.connect = Some(method.handler),
-                    Method::TRACE => method_route.trace = Some(method.handler),
-                    v => unreachable!("unsupported method: {:?}", v),
-                }
+                validate_route(path, method)?;
+                let mut route = method.clone();
+                route.path = path.clone();
+                method_route.insert(path, route)?;
+            }
+            match router.insert(path.clone(), method_route) {
+                Ok(()) => {}
+                Err(InsertError::Conflict { with }) => bail!(
+                    "route {} conflicts with route {}, a request could match both",
+                    path,
+                    with
+                ),
+                Err(e) => bail!("invalid route {}: {}", path, e),
             }
-            router.insert(path, method_route)?;
         }
         Ok(router)
     }
@@ -68,12 +98,38 @@ impl SwappableAppRouter {
         AppRouter(self.routers.load_full())
     }
 
-    pub fn swap(&self, code: impl Into<String>, routes: ProjectRoutes) -> Result<()> {
-        let router = Self::get_router(routes)?;
-        let inner = AppRouterInner::new(code, router)?;
+    // the current router is kept if the new code or config is invalid
+    pub fn swap(&self, code: impl Into<String>, config: ProjectConfig) -> Result<()> {
+        let code = code.into();
+        config.validate_handlers(&code)?;
+        self.store(code, config)
+    }
+
+    // serve the code of another router, its handlers were validated when it was built
+    pub fn swap_to(&self, other: &AppRouter) -> Result<()> {
+        self.store(other.code.clone(), other.config.clone())
+    }
+
+    fn store(&self, code: String, config: ProjectConfig) -> Result<()> {
+        let router = Self::get_router(&config.routes)?;
+        let current = self.routers.load();
+        let (caches, limiter) = (current.caches.clone(), current.limiter.clone());
+        let inner = AppRouterInner::new(code, router, config, caches, limiter)?;
         self.routers.store(Arc::new(inner));
         Ok(())
     }
+
+    // swap in a rebuild of the code, e.g. on file changes, counted by the metrics
+    pub fn reload(&self, build: impl FnOnce() -> Result<(ProjectConfig, String)>) -> Result<()> {
+        let ret = build().and_then(|(config, code)| self.swap(code, config));
+        self.reloads.record(ret.is_ok());
+        ret
+    }
+
+    // load the `caches` of the js code from the file, and save them there on every change
+    pub fn persist_caches(&self, path: impl Into<PathBuf>) -> Result<()> {
+        self.routers.load().caches.persist_to(path)
+    }
 }
 
 #[allow(elided_named_lifetimes)]
@@ -82,33 +138,237 @@ impl AppRouter {
         &'m self,
         method: Method,
         path: &'p str,
-    ) -> Result<Match<&str>, AppError>
+    ) -> Result<Match<&ProjectRoute>, AppError>
     where
         'p: 'm,
     {
-        println!("match_it: {:?}, {:?}", method, path);
         let Ok(ret) = self.router.at(path) else {
             return Err(AppError::RoutePathNotFound(path.to_string()));
         };
-        let s = match method {
-            Method::GET => ret.value.get.as_deref(),
-            Method::POST => ret.value.post.as_deref(),
-            Method::PUT => ret.value.put.as_deref(),
-            Method::DELETE => ret.value.delete.as_deref(),
-            Method::PATCH => ret.value.patch.as_deref(),
-            Method::OPTIONS => ret.value.options.as_deref(),
-            Method::HEAD => ret.value.head.as_deref(),
-            Method::CONNECT => ret.value.connect.as_deref(),
-            Method::TRACE => ret.value.trace.as_deref(),
-            v => unreachable!("unsupported method: {:?}", v),
-        }
-        .ok_or_else(|| AppError::RouteMethodNotAllowed(method))?;
+        let s = ret
+            .value
+            .get(&method)
+            .ok_or_else(|| AppError::RouteMethodNotAllowed(method))?;
 
         Ok(Match {
             value: s,
             params: ret.params,
         })
     }
+
+    // follow the internal rewrites of the matched routes, return the final path
+    pub fn rewrite(&self, method: &Method, path: &str) -> Result<String, AppError> {
+        let mut path = path.to_string();
+        for _ in 0..MAX_REWRITES {
+            let matched = self.match_it(method.clone(), &path)?;
+            let RouteAction::Rewrite(target) = &matched.value.action else {
+                return Ok(path);
+            };
+            path = substitute_params(target, &matched.params);
+        }
+        Err(AppError::TooManyRewrites(path))
+    }
+
+    // names of the middleware handlers that apply to the path, in order
+    pub fn middleware_for(&self, path: &str) -> Vec<String> {
+        self.middleware
+            .iter()
+            .filter(|m| m.matches(path))
+            .map(|m| m.handler.clone())
+            .collect()
+    }
+
+    // the auth guard of the route, falling back to the project level one
+    pub fn auth_for<'a>(&'a self, route: &'a ProjectRoute) -> Option<&'a AuthConfig> {
+        route.auth.as_ref().or(self.auth.as_ref())
+    }
+
+    // a request is charged one bucket per limit, in this order:
+    //   1. the project level and then the route limits not keyed by subject, before the
+    //      credentials are verified
+    //   2. the limits keyed by subject once they are, valid or not, so guessing them is limited
+    //      as well. keyed by ip without a subject
+    pub fn check_rate_limit(&self, route: &ProjectRoute, client: &Client) -> Result<(), AppError> {
+        let limits = self.rate_limits(route);
+        for (scope, config) in limits.filter(|(_, c)| c.key != RateLimitKey::Subject) {
+            self.limiter.check(&scope, config, client)?;
+        }
+        Ok(())
+    }
+
+    pub fn check_subject_rate_limit(
+        &self,
+        route: &ProjectRoute,
+        client: &Client,
+    ) -> Result<(), AppError> {
+        let limits = self.rate_limits(route);
+        for (scope, config) in limits.filter(|(_, c)| c.key == RateLimitKey::Subject) {
+            self.limiter.check(&scope, config, client)?;
+        }
+        Ok(())
+    }
+
+    // (scope, limit) of the project and the route
+    fn rate_limits<'a>(
+        &'a self,
+        route: &'a ProjectRoute,
+    ) -> impl Iterator<Item = (String, &'a RateLimitConfig)> {
+        let project = self.rate_limit.as_ref().map(|c| ("*".to_string(), c));
+        let route = route
+            .rate_limit
+            .as_ref()
+            .map(|c| (format!("{} {}", route.method, route.path), c));
+        project.into_iter().chain(route)
+    }
+
+    // the cors policy of the route, falling back to the project level one
+    pub fn cors_for<'a>(&'a self, route: Option<&'a ProjectRoute>) -> Option<&'a CorsConfig> {
+        route.and_then(|r| r.cors.as_ref()).or(self.cors.as_ref())
+    }
+}
+
+impl MethodRoute {
+    fn insert(&mut self, path: &str, route: ProjectRoute) -> Result<()> {
+        let existing = match &route.method {
+            RouteMethod::Any => self.any.as_ref(),
+            RouteMethod::Method(method) => self.methods.get(method),
+        };
+        if let Some(existing) = existing {
+            bail!(
+                "{} {} is registered twice: `{}` and `{}`",
+                route.method,
+                path,
+                existing.action,
+                route.action
+            );
+        }
+
+        match &route.method {
+            RouteMethod::Any => self.any = Some(route),
+            RouteMethod::Method(method) => {
+                self.methods.insert(method.clone(), route);
+            }
+        }
+        Ok(())
+    }
+
+    // explicitly registered methods take precedence over `ANY`
+    fn get(&self, method: &Method) -> Option<&ProjectRoute> {
+        self.methods.get(method).or(self.any.as_ref())
+    }
+}
+
+impl RedirectConfig {
+    pub fn to_response(&self, params: &Params, query: Option<&str>) -> Response {
+        let mut location = substitute_params(&self.to, params);
+        // keep the original query string unless the target has its own
+        if let Some(query) = query {
+            if !location.contains('?') {
+                location = format!("{}?{}", location, query);
+            }
+        }
+        // the status and the target are checked by validate_route
+        Response::builder()
+            .status(self.status)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .unwrap_or_else(|_| {
+                let mut res = Response::default();
+                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                res
+            })
+    }
+}
+
+// the params (e.g. `id` for `{id}`, `rest` for `{*rest}`) used in a route path or target
+fn path_params(path: &str) -> Vec<&str> {
+    path.split('{')
+        .skip(1)
+        .filter_map(|s| s.split_once('}'))
+        .map(|(name, _)| name.trim_start_matches('*'))
+        .collect()
+}
+
+// replace `{name}` / `{*name}` in the template with the matched params, in a single pass
+// so the values are never substituted themselves
+pub(crate) fn substitute_params(template: &str, params: &Params) -> String {
+    let mut ret = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            break;
+        };
+        ret.push_str(&rest[..start]);
+        let placeholder = &rest[start..start + len + 1];
+        let name = placeholder[1..len].trim_start_matches('*');
+        ret.push_str(params.get(name).unwrap_or(placeholder));
+        rest = &rest[start + len + 1..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+// replace the path of the uri, keeping the query string
+pub(crate) fn rewrite_uri(uri: &Uri, path: &str) -> Result<Uri> {
+    let path_and_query = match uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = uri.clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+fn validate_route(path: &str, route: &ProjectRoute) -> Result<()> {
+    let target = match &route.action {
+        RouteAction::Handler(_) => return Ok(()),
+        RouteAction::Redirect(redirect) => {
+            if !REDIRECT_STATUSES.contains(&redirect.status) {
+                bail!(
+                    "{} {}: invalid redirect status {}",
+                    route.method,
+                    path,
+                    redirect.status
+                );
+            }
+            // a valid header value and url, the params are parts of the request path so they
+            // keep it valid
+            if !redirect.to.bytes().all(|b| b.is_ascii_graphic()) {
+                bail!(
+                    "{} {}: redirect target {} is not a valid location",
+                    route.method,
+                    path,
+                    redirect.to
+                );
+            }
+            &redirect.to
+        }
+        RouteAction::Rewrite(to) => {
+            if !to.starts_with('/') {
+                bail!(
+                    "{} {}: rewrite target {} must be a path",
+                    route.method,
+                    path,
+                    to
+                );
+            }
+            to
+        }
+    };
+
+    let params = path_params(path);
+    for name in path_params(target) {
+        if !params.contains(&name) {
+            bail!(
+                "{} {}: target {} uses param `{}` which is not in the route path",
+                route.method,
+                path,
+                target,
+                name
+            );
+        }
+    }
+    Ok(())
 }
 
 impl Deref for AppRouter {
@@ -119,10 +379,272 @@ impl Deref for AppRouter {
     }
 }
 impl AppRouterInner {
-    pub fn new(code: impl Into<String>, router: Router<MethodRoute>) -> Result<Self> {
+    pub fn new(
+        code: impl Into<String>,
+        router: Router<MethodRoute>,
+        config: ProjectConfig,
+        caches: Arc<CacheStorage>,
+        limiter: Arc<RateLimiter>,
+    ) -> Result<Self> {
         Ok(Self {
             code: code.into(),
+            config: config.clone(),
             router,
+            cors: config.cors,
+            middleware: config.middleware,
+            auth: config.auth,
+            rate_limit: config.rate_limit,
+            limiter,
+            compression: config.compression,
+            cache: config.cache.map(ResponseCache::new),
+            caches,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(yaml: &str) -> ProjectConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn rewrite_should_follow_targets() -> Result<()> {
+        let router = SwappableAppRouter::try_new(
+            "",
+            config(
+                r#"
+name: test
+routes:
+  /a/{id}:
+    - method: GET
+      rewrite: /b/{id}
+  /b/{id}:
+    - method: GET
+      rewrite: /c/{id}/x
+  /c/{id}/x:
+    - method: GET
+      handler: hello
+"#,
+            ),
+        )?
+        .load();
+
+        assert_eq!(router.rewrite(&Method::GET, "/a/1")?, "/c/1/x");
+        assert_eq!(router.rewrite(&Method::GET, "/c/1/x")?, "/c/1/x");
+        Ok(())
+    }
+
+    #[test]
+    fn match_it_should_support_extension_and_any_methods() -> Result<()> {
+        let router = SwappableAppRouter::try_new(
+            "",
+            config(
+                r#"
+name: test
+routes:
+  /dav/{*path}:
+    - method: propfind
+      handler: propfind
+    - method: ANY
+      handler: fallback
+  /cache:
+    - method: PURGE
+      handler: purge
+"#,
+            ),
+        )?
+        .load();
+
+        let handler = |method: &str, path: &str| {
+            let method = Method::from_bytes(method.as_bytes()).unwrap();
+            router
+                .match_it(method, path)
+                .map(|m| match &m.value.action {
+                    RouteAction::Handler(name) => name.clone(),
+                    _ => unreachable!(),
+                })
+        };
+        assert_eq!(handler("PROPFIND", "/dav/a/b")?, "propfind");
+        assert_eq!(handler("GET", "/dav/a/b")?, "fallback");
+        assert_eq!(handler("MKCOL", "/dav/a")?, "fallback");
+        assert_eq!(handler("PURGE", "/cache")?, "purge");
+        assert!(matches!(
+            handler("GET", "/cache"),
+            Err(AppError::RouteMethodNotAllowed(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn get_router_should_reject_unknown_params() {
+        let ret = SwappableAppRouter::try_new(
+            "",
+            config(
+                r#"
+name: test
+routes:
+  /old/{id}:
+    - method: GET
+      redirect:
+        to: /new/{name}
+"#,
+            ),
+        );
+        let err = ret.err().unwrap().to_string();
+        assert!(err.contains("`name`"), "{}", err);
+    }
+
+    #[test]
+    fn get_router_should_reject_invalid_redirects() {
+        let err = |to: &str, status: u16| {
+            let yaml = format!(
+                "name: test\nroutes:\n  /old:\n    - method: GET\n      redirect: {{ to: '{}', status: {} }}",
+                to, status
+            );
+            SwappableAppRouter::try_new("", config(&yaml))
+                .err()
+                .map(|e| e.to_string())
+        };
+        assert!(err("/new", 301).is_none());
+        assert!(err("/new", 304).unwrap().contains("status 304"));
+        assert!(err("/new", 300).unwrap().contains("status 300"));
+        assert!(err("/new page", 302).unwrap().contains("location"));
+        assert!(err("/nouvelle-pagé", 302).unwrap().contains("location"));
+    }
+
+    #[test]
+    fn rate_limits_should_survive_swaps_and_charge_one_bucket_each() -> Result<()> {
+        let yaml = r#"
+name: test
+rate_limit: { requests: 1, period: 60, key: subject }
+routes:
+  /a:
+    - method: GET
+      handler: hello
+      rate_limit: { requests: 2, period: 60 }
+"#;
+        let code = "(function(){async function hello(req){}return{hello:hello};})();";
+        let router = SwappableAppRouter::try_new(code, config(yaml))?;
+        let headers = axum::http::HeaderMap::new();
+        let auth = |subject: &str| crate::AuthInfo {
+            scheme: "basic".to_string(),
+            subject: Some(subject.to_string()),
+            claims: Default::default(),
+        };
+        let (alice, bob) = (auth("alice"), auth("bob"));
+        let client = |auth| Client {
+            peer: crate::listener::Peer::Tcp(([1, 1, 1, 1], 4000).into()),
+            headers: &headers,
+            auth,
+        };
+        let check = |auth| {
+            let loaded = router.load();
+            let route = loaded.match_it(Method::GET, "/a")?.value;
+            loaded.check_subject_rate_limit(route, &client(auth))
+        };
+        let check_ip = || {
+            let loaded = router.load();
+            let route = loaded.match_it(Method::GET, "/a")?.value;
+            loaded.check_rate_limit(route, &client(None))
+        };
+
+        // the limit keyed by subject is only charged once the credentials are verified
+        assert!(check_ip().is_ok());
+        router.swap(code, config(yaml))?;
+        assert!(check_ip().is_ok());
+        assert!(check_ip().is_err());
+        assert!(check(Some(&alice)).is_ok());
+        assert!(check(Some(&alice)).is_err());
+        assert!(check(Some(&bob)).is_ok());
+        // by ip without a subject, still once per request
+        assert!(check(None).is_ok());
+        assert!(check(None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn swap_should_keep_old_router_on_missing_handler() -> Result<()> {
+        let code = "(function(){async function hello(req){}return{hello:hello};})();";
+        let yaml = |handler: &str| {
+            config(&format!(
+                "name: test\nroutes:\n  /a:\n    - method: GET\n      handler: {}",
+                handler
+            ))
+        };
+        let router = SwappableAppRouter::try_new(code, yaml("hello"))?;
+
+        assert!(router.swap(code, yaml("helo")).is_err());
+        let loaded = router.load();
+        let matched = loaded.match_it(Method::GET, "/a")?;
+        assert!(matches!(&matched.value.action, RouteAction::Handler(h) if h == "hello"));
+        Ok(())
+    }
+
+    #[test]
+    fn get_router_should_detect_conflicts() {
+        let err = |yaml: &str| {
+            SwappableAppRouter::try_new("", config(yaml))
+                .err()
+                .unwrap()
+                .to_string()
+        };
+
+        let msg = err(r#"
+name: test
+routes:
+  /a:
+    - method: GET
+      handler: hello
+    - method: get
+      handler: hello2
+"#);
+        assert_eq!(
+            msg,
+            "GET /a is registered twice: `handler hello` and `handler hello2`"
+        );
+
+        let msg = err(r#"
+name: test
+routes:
+  /api/{name}:
+    - method: GET
+      handler: hello
+  /api/{id}:
+    - method: GET
+      handler: hello2
+"#);
+        assert_eq!(
+            msg,
+            "route /api/{id} conflicts with route /api/{name}, a request could match both"
+        );
+    }
+
+    #[test]
+    fn redirect_should_substitute_params() {
+        let mut router = Router::new();
+        router.insert("/old/{id}/{*rest}", ()).unwrap();
+        let matched = router.at("/old/1/a/b").unwrap();
+        let redirect = RedirectConfig {
+            to: "https://example.com/new/{id}/{*rest}".to_string(),
+            status: 302,
+        };
+
+        let res = redirect.to_response(&matched.params, Some("x=1"));
+        assert_eq!(res.status(), StatusCode::FOUND);
+        assert_eq!(res.headers()[LOCATION], "https://example.com/new/1/a/b?x=1");
+    }
+
+    #[test]
+    fn substitute_params_should_not_substitute_values() {
+        let mut router = Router::new();
+        router.insert("/old/{id}/{idx}/{*rest}", ()).unwrap();
+        let matched = router.at("/old/{idx}/2/{id}").unwrap();
+        assert_eq!(
+            substitute_params("/new/{idx}/{id}/{*rest}/{x}/{", &matched.params),
+            "/new/2/{idx}/{id}/{x}/{"
+        );
+    }
+}
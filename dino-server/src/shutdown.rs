@@ -0,0 +1,173 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+use crate::{AppState, JsWorker};
+
+// the `unload` listeners still running are stopped after this long, one looping forever
+// would keep the process alive
+const UNLOAD_TIMEOUT: Duration = Duration::from_secs(5);
+
+// requests being handled and handled so far, to drain and summarize on shutdown
+#[derive(Debug, Clone, Default)]
+pub(crate) struct RequestStats {
+    inflight: Arc<AtomicUsize>,
+    served: Arc<AtomicU64>,
+}
+
+pub(crate) struct InflightGuard(RequestStats);
+
+impl RequestStats {
+    pub fn track(&self) -> InflightGuard {
+        self.inflight.fetch_add(1, Ordering::SeqCst);
+        InflightGuard(self.clone())
+    }
+
+    pub fn inflight(&self) -> usize {
+        self.inflight.load(Ordering::SeqCst)
+    }
+
+    pub fn served(&self) -> u64 {
+        self.served.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for InflightGuard {
+    fn drop(&mut self) {
+        self.0.inflight.fetch_sub(1, Ordering::SeqCst);
+        self.0.served.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// flips to true once SIGINT or SIGTERM is received
+pub(crate) fn listen(stats: RequestStats) -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+    tokio::spawn(async move {
+        signal().await;
+        info!(
+            "Shutting down, waiting for {} in-flight requests",
+            stats.inflight()
+        );
+        let _ = tx.send(true);
+    });
+    rx
+}
+
+pub(crate) async fn wait(mut rx: watch::Receiver<bool>) {
+    // an error means the sender is gone, which only happens after the signal
+    let _ = rx.wait_for(|v| *v).await;
+}
+
+// resolves once the draining after the signal exceeds the timeout
+pub(crate) async fn deadline(rx: watch::Receiver<bool>, timeout: Duration) {
+    wait(rx).await;
+    tokio::time::sleep(timeout).await;
+}
+
+async fn signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("failed to listen for ctrl-c: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                warn!("failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+// run the `unload` listeners of every tenant's code
+pub(crate) async fn unload(state: &AppState) {
+    unload_within(state, UNLOAD_TIMEOUT).await
+}
+
+async fn unload_within(state: &AppState, timeout: Duration) {
+    let routers: Vec<_> = state
+        .tenants
+        .load()
+        .routers()
+        .into_iter()
+        .map(|(host, router)| (host, router.load()))
+        .collect();
+    let deadline = Instant::now() + timeout;
+    // js runs synchronously, off the async workers like the handlers
+    let unload = tokio::task::spawn_blocking(move || {
+        for (host, router) in routers {
+            let ret = JsWorker::try_new_until(&router.code, deadline)
+                .and_then(|worker| worker.with_caches(router.caches.clone()))
+                .and_then(|worker| worker.unload());
+            if let Err(e) = ret {
+                warn!("unload listeners of {} failed: {:?}", host, e);
+            }
+            router.caches.flush();
+        }
+    });
+    // the js is interrupted at the deadline, this bounds the rest, e.g. saving the caches
+    if tokio::time::timeout(timeout * 2, unload).await.is_err() {
+        warn!("unload listeners still running after {:?}", timeout * 2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unload_should_stop_looping_listeners() -> anyhow::Result<()> {
+        use std::collections::HashMap;
+
+        use crate::{ProjectConfig, SwappableAppRouter, Tenants};
+
+        let code = r#"(function(){
+            addEventListener("unload", () => { while (true) {} });
+            async function hello(req){}
+            return{hello};
+        })();"#;
+        let config: ProjectConfig = serde_yaml::from_str("{ name: app, routes: {} }")?;
+        let router = SwappableAppRouter::try_new(code, config)?;
+        let tenants = Tenants::try_new([("a.com".to_string(), router)], HashMap::new(), None)?;
+        let state = AppState::new(tenants);
+
+        let started = Instant::now();
+        unload_within(&state, Duration::from_millis(100)).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        Ok(())
+    }
+
+    #[test]
+    fn request_stats_should_track_inflight() {
+        let stats = RequestStats::default();
+        let a = stats.track();
+        let b = stats.track();
+        assert_eq!(stats.inflight(), 2);
+        drop(a);
+        assert_eq!((stats.inflight(), stats.served()), (1, 1));
+        drop(b);
+        assert_eq!((stats.inflight(), stats.served()), (0, 2));
+    }
+}
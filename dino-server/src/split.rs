@@ -0,0 +1,293 @@
+use anyhow::{Result, bail};
+use axum::{
+    http::{HeaderMap, HeaderValue, header::COOKIE, header::SET_COOKIE},
+    response::Response,
+};
+
+use crate::{
+    SplitRule, SwappableAppRouter,
+    utils::{is_valid_label, random_u64},
+};
+
+// the deployment that served the request
+pub const X_DEPLOYMENT: &str = "x-dino-deployment";
+// pins a client to the deployment it was assigned to
+pub const DEPLOYMENT_COOKIE: &str = "dino-deployment";
+const STICKY_MAX_AGE: u64 = 24 * 60 * 60;
+
+// the deployments sharing the traffic of a host, the primary one serves what the others don't
+pub struct Split {
+    primary: String,
+    canaries: Vec<Canary>,
+}
+
+struct Canary {
+    deployment: String,
+    router: SwappableAppRouter,
+    rule: SplitRule,
+}
+
+// the deployment picked for a request
+pub struct Choice<'a> {
+    pub deployment: &'a str,
+    // None for the primary deployment, served by the router of the tenant
+    pub router: Option<&'a SwappableAppRouter>,
+    // picked by weight, the client is pinned to it with the cookie
+    assigned: bool,
+}
+
+impl Split {
+    // `canaries` are (deployment, router, rule)
+    pub fn try_new(
+        primary: impl Into<String>,
+        canaries: impl IntoIterator<Item = (String, SwappableAppRouter, SplitRule)>,
+    ) -> Result<Self> {
+        let primary = primary.into();
+        let canaries: Vec<_> = canaries
+            .into_iter()
+            .map(|(deployment, router, rule)| Canary {
+                deployment,
+                router,
+                rule,
+            })
+            .collect();
+        let mut names = vec![primary.as_str()];
+        for canary in &canaries {
+            if names.contains(&canary.deployment.as_str()) {
+                bail!("deployment {} is split twice", canary.deployment);
+            }
+            names.push(&canary.deployment);
+        }
+        // the names go into a header and a cookie, and are the hashes of the preview hosts
+        if let Some(name) = names.iter().find(|name| !is_valid_label(name)) {
+            bail!(
+                "invalid deployment name `{}`, expect lowercase letters, digits and `-`",
+                name
+            );
+        }
+        let total: u32 = canaries.iter().map(|c| c.rule.weight as u32).sum();
+        if total > 100 {
+            bail!("the weights of the split add up to {}%, over 100%", total);
+        }
+        Ok(Self { primary, canaries })
+    }
+
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    // (deployment, router, rule) of the deployments other than the primary one
+    pub fn canaries(&self) -> impl Iterator<Item = (&str, &SwappableAppRouter, &SplitRule)> {
+        self.canaries
+            .iter()
+            .map(|c| (c.deployment.as_str(), &c.router, &c.rule))
+    }
+
+    // the rules first, then the deployment the client is pinned to, then by weight
+    pub fn choose(&self, headers: &HeaderMap) -> Choice<'_> {
+        let cookies = cookies(headers);
+        if let Some(canary) = self.canaries.iter().find(|c| c.matches(headers, &cookies)) {
+            return canary.choice(false);
+        }
+
+        let pinned = cookies
+            .iter()
+            .find(|(name, _)| *name == DEPLOYMENT_COOKIE)
+            .map(|(_, value)| *value);
+        if let Some(pinned) = pinned {
+            if pinned == self.primary {
+                return self.primary_choice(false);
+            }
+            // a canary taken down to 0% gets its clients back to the primary deployment
+            let canary = self
+                .canaries
+                .iter()
+                .find(|c| c.deployment == pinned && c.rule.weight > 0);
+            if let Some(canary) = canary {
+                return canary.choice(false);
+            }
+        }
+
+        let mut roll = roll();
+        for canary in &self.canaries {
+            if roll < canary.rule.weight {
+                return canary.choice(true);
+            }
+            roll -= canary.rule.weight;
+        }
+        self.primary_choice(true)
+    }
+
+    fn primary_choice(&self, assigned: bool) -> Choice<'_> {
+        Choice {
+            deployment: &self.primary,
+            router: None,
+            assigned,
+        }
+    }
+}
+
+impl Canary {
+    fn matches(&self, headers: &HeaderMap, cookies: &[(&str, &str)]) -> bool {
+        let header = self.rule.headers.iter().any(|(name, value)| {
+            headers
+                .get_all(name.as_str())
+                .iter()
+                .any(|v| v.as_bytes() == value.as_bytes())
+        });
+        header
+            || self
+                .rule
+                .cookies
+                .iter()
+                .any(|(name, value)| cookies.contains(&(name.as_str(), value.as_str())))
+    }
+
+    fn choice(&self, assigned: bool) -> Choice<'_> {
+        Choice {
+            deployment: &self.deployment,
+            router: Some(&self.router),
+            assigned,
+        }
+    }
+}
+
+impl Choice<'_> {
+    // tell which deployment served the response, and pin a newly assigned client to it
+    pub fn apply(&self, res: &mut Response) {
+        let headers = res.headers_mut();
+        // the names are checked by Split::try_new
+        if let Ok(value) = HeaderValue::from_str(self.deployment) {
+            headers.insert(X_DEPLOYMENT, value);
+        }
+        if self.assigned {
+            let cookie = format!(
+                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                DEPLOYMENT_COOKIE, self.deployment, STICKY_MAX_AGE
+            );
+            if let Ok(value) = HeaderValue::from_str(&cookie) {
+                headers.append(SET_COOKIE, value);
+            }
+        }
+    }
+}
+
+// (name, value) of every cookie of the request
+fn cookies(headers: &HeaderMap) -> Vec<(&str, &str)> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect()
+}
+
+// 0..100
+fn roll() -> u8 {
+    (random_u64() % 100) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::ProjectConfig;
+
+    fn router(name: &str) -> SwappableAppRouter {
+        let config: ProjectConfig =
+            serde_yaml::from_str(&format!("{{ name: {}, routes: {{}} }}", name)).unwrap();
+        SwappableAppRouter::try_new(name, config).unwrap()
+    }
+
+    fn rule(weight: u8) -> SplitRule {
+        SplitRule {
+            weight,
+            ..Default::default()
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn split_should_follow_rules_cookie_and_weights() -> Result<()> {
+        let canary = SplitRule {
+            weight: 0,
+            headers: HashMap::from([("x-canary".to_string(), "1".to_string())]),
+            cookies: HashMap::from([("beta".to_string(), "yes".to_string())]),
+        };
+        let split = Split::try_new(
+            "blue",
+            [
+                ("green".to_string(), router("green"), canary),
+                ("all".to_string(), router("all"), rule(100)),
+            ],
+        )?;
+        let choose = |pairs: &[(&'static str, &str)]| {
+            let choice = split.choose(&headers(pairs));
+            (choice.deployment.to_string(), choice.assigned)
+        };
+
+        assert_eq!(choose(&[("x-canary", "1")]), ("green".to_string(), false));
+        assert_eq!(
+            choose(&[("cookie", "a=b; beta=yes")]),
+            ("green".to_string(), false)
+        );
+        // a client pinned to a deployment stays there, unless it's at 0%
+        let pinned = "dino-deployment=blue";
+        assert_eq!(choose(&[("cookie", pinned)]), ("blue".to_string(), false));
+        let pinned = "dino-deployment=green";
+        assert_eq!(choose(&[("cookie", pinned)]), ("all".to_string(), true));
+        assert_eq!(choose(&[]), ("all".to_string(), true));
+
+        let mut res = Response::new(Default::default());
+        split.choose(&HeaderMap::new()).apply(&mut res);
+        assert_eq!(res.headers()[X_DEPLOYMENT], "all");
+        assert!(
+            res.headers()[SET_COOKIE]
+                .to_str()?
+                .starts_with("dino-deployment=all;")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn split_should_spread_by_weight() -> Result<()> {
+        let split = Split::try_new("blue", [("green".to_string(), router("green"), rule(30))])?;
+        let green = (0..10_000)
+            .filter(|_| split.choose(&HeaderMap::new()).router.is_some())
+            .count();
+        assert!((2_500..3_500).contains(&green), "{} of 10000", green);
+        Ok(())
+    }
+
+    #[test]
+    fn split_should_reject_invalid_deployments() {
+        let err = |canaries: Vec<(&str, u8)>| {
+            let canaries = canaries
+                .into_iter()
+                .map(|(name, weight)| (name.to_string(), router(name), rule(weight)));
+            Split::try_new("blue", canaries)
+                .err()
+                .map(|e| e.to_string())
+        };
+        assert!(
+            err(vec![("green", 60), ("red", 50)])
+                .unwrap()
+                .contains("110%")
+        );
+        assert!(err(vec![("blue", 10)]).unwrap().contains("twice"));
+        for name in ["a b", "a_b", "v1.2", "Green"] {
+            assert!(err(vec![(name, 10)]).unwrap().contains("invalid"));
+        }
+        assert!(err(vec![("green", 60), ("red", 40)]).is_none());
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+
+use anyhow::{Context as _, Result};
+use axum::http::{HeaderMap, Method, StatusCode};
+use opentelemetry::{
+    Context, KeyValue,
+    propagation::{Extractor, TextMapPropagator},
+    trace::{SpanKind, Status, TraceContextExt, Tracer, TracerProvider},
+};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{
+    Resource,
+    propagation::TraceContextPropagator,
+    trace::{self, Sampler, SdkTracer, SdkTracerProvider},
+};
+use tracing::warn;
+
+use crate::{TelemetryConfig, metrics::RequestLabels};
+
+// the spans of a request, continuing the trace of the caller if it sent a `traceparent`.
+// without a collector the trace context is still propagated, nothing is exported
+pub struct Telemetry {
+    provider: SdkTracerProvider,
+    tracer: SdkTracer,
+    propagator: TraceContextPropagator,
+}
+
+// the http headers as seen by the propagator
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Telemetry {
+    pub fn try_new(config: Option<&TelemetryConfig>) -> Result<Self> {
+        let mut builder = SdkTracerProvider::builder();
+        if let Some(config) = config {
+            let exporter = SpanExporter::builder()
+                .with_http()
+                .with_endpoint(&config.endpoint)
+                .build()
+                .with_context(|| format!("failed to export the spans to {}", config.endpoint))?;
+            let resource = Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build();
+            let sampler = Sampler::TraceIdRatioBased(config.sample_ratio);
+            builder = builder
+                .with_batch_exporter(exporter)
+                .with_resource(resource)
+                .with_sampler(Sampler::ParentBased(Box::new(sampler)));
+        }
+        Ok(Self::new(builder.build()))
+    }
+
+    fn new(provider: SdkTracerProvider) -> Self {
+        Self {
+            tracer: provider.tracer("dino-server"),
+            provider,
+            propagator: TraceContextPropagator::new(),
+        }
+    }
+
+    // the spans are exported as soon as they end
+    #[cfg(test)]
+    pub(crate) fn in_memory() -> (Self, opentelemetry_sdk::trace::InMemorySpanExporter) {
+        let exporter = opentelemetry_sdk::trace::InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        (Self::new(provider), exporter)
+    }
+
+    // the server span of a request, named after the route once it's matched
+    pub fn request(
+        &self,
+        headers: &HeaderMap,
+        method: &Method,
+        path: &str,
+        request_id: &str,
+    ) -> Context {
+        let parent = self.propagator.extract(&HeaderExtractor(headers));
+        let span = self
+            .tracer
+            .span_builder(method.to_string())
+            .with_kind(SpanKind::Server)
+            .with_attributes([
+                KeyValue::new("http.request.method", method.to_string()),
+                KeyValue::new("url.path", path.to_string()),
+                KeyValue::new("dino.request_id", request_id.to_string()),
+            ])
+            .start_with_context(&self.tracer, &parent);
+        parent.with_span(span)
+    }
+
+    // a stage of the request, e.g. `routing`, ended when dropped
+    pub fn stage(&self, name: &'static str, cx: &Context) -> trace::Span {
+        self.tracer.start_with_context(name, cx)
+    }
+
+    // `traceparent` (and `tracestate`) continuing the trace from the span of the context,
+    // for the handlers to forward on their outbound requests
+    pub fn headers(&self, cx: &Context) -> HashMap<String, String> {
+        let mut headers = HashMap::new();
+        self.propagator.inject_context(cx, &mut headers);
+        // no `tracestate` without one from the caller
+        headers.retain(|_, value| !value.is_empty());
+        headers
+    }
+
+    // flush the spans not exported yet, blocks until the collector answers
+    pub fn shutdown(&self) {
+        if let Err(e) = self.provider.shutdown() {
+            warn!("failed to flush the spans: {}", e);
+        }
+    }
+}
+
+impl Default for Telemetry {
+    fn default() -> Self {
+        Self::new(SdkTracerProvider::builder().build())
+    }
+}
+
+// what is known of the request once it's served
+pub fn finish(cx: &Context, labels: &RequestLabels, status: StatusCode) {
+    let span = cx.span();
+    if !labels.route.is_empty() {
+        span.update_name(format!("{} {}", labels.method, labels.route));
+        span.set_attribute(KeyValue::new("http.route", labels.route.clone()));
+    }
+    for (key, value) in [
+        ("dino.tenant", &labels.tenant),
+        ("dino.handler", &labels.handler),
+    ] {
+        if !value.is_empty() {
+            span.set_attribute(KeyValue::new(key, value.clone()));
+        }
+    }
+    span.set_attribute(KeyValue::new(
+        "http.response.status_code",
+        status.as_u16() as i64,
+    ));
+    if status.is_server_error() {
+        span.set_status(Status::error(status.to_string()));
+    }
+}
+
+// mark the span of the context failed, e.g. the js handler threw
+pub fn fail(cx: &Context, e: &anyhow::Error) {
+    cx.span().set_status(Status::error(e.to_string()));
+}
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{SpanId, TraceId};
+
+    use super::*;
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+
+    #[test]
+    fn telemetry_should_continue_the_trace_of_the_caller() -> Result<()> {
+        let (telemetry, exporter) = Telemetry::in_memory();
+        let mut headers = HeaderMap::new();
+        let traceparent = format!("00-{}-00f067aa0ba902b7-01", TRACE_ID);
+        headers.insert("traceparent", traceparent.parse()?);
+
+        let cx = telemetry.request(&headers, &Method::GET, "/api/1", "abc");
+        let js = cx.with_span(telemetry.stage("js execution", &cx));
+        let forwarded = telemetry.headers(&js);
+        drop(js);
+        let labels = RequestLabels {
+            tenant: "app".to_string(),
+            route: "/api/{id}".to_string(),
+            handler: "hello".to_string(),
+            method: "GET".to_string(),
+        };
+        finish(&cx, &labels, StatusCode::BAD_GATEWAY);
+        drop(cx);
+
+        let spans = exporter.get_finished_spans()?;
+        let [js, request] = &spans[..] else {
+            panic!("{} spans", spans.len());
+        };
+        assert_eq!(request.name, "GET /api/{id}");
+        assert_eq!(request.span_kind, SpanKind::Server);
+        assert_eq!(
+            request.span_context.trace_id(),
+            TraceId::from_hex(TRACE_ID)?
+        );
+        assert_eq!(
+            request.parent_span_id,
+            SpanId::from_hex("00f067aa0ba902b7")?
+        );
+        assert!(matches!(request.status, Status::Error { .. }));
+        let attribute = |key: &str| {
+            request
+                .attributes
+                .iter()
+                .find(|kv| kv.key.as_str() == key)
+                .map(|kv| kv.value.to_string())
+        };
+        assert_eq!(attribute("http.route").as_deref(), Some("/api/{id}"));
+        assert_eq!(attribute("dino.request_id").as_deref(), Some("abc"));
+        assert_eq!(
+            attribute("http.response.status_code").as_deref(),
+            Some("502")
+        );
+
+        assert_eq!(js.parent_span_id, request.span_context.span_id());
+        assert_eq!(
+            forwarded["traceparent"],
+            format!("00-{}-{}-01", TRACE_ID, js.span_context.span_id())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn telemetry_should_start_a_trace_without_a_caller() -> Result<()> {
+        let (telemetry, exporter) = Telemetry::in_memory();
+        let cx = telemetry.request(&HeaderMap::new(), &Method::POST, "/", "abc");
+        let forwarded = telemetry.headers(&cx);
+        drop(cx);
+
+        let spans = exporter.get_finished_spans()?;
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "POST");
+        assert_eq!(spans[0].parent_span_id, SpanId::INVALID);
+        let trace_id = spans[0].span_context.trace_id();
+        assert!(forwarded["traceparent"].contains(&trace_id.to_string()));
+        assert!(!forwarded.contains_key("tracestate"));
+        Ok(())
+    }
+}
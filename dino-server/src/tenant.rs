@@ -0,0 +1,298 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Result, bail};
+
+use crate::{Split, SwappableAppRouter};
+
+// the routers of the tenants by host, resolved in order:
+// 1. aliases are replaced by their target host
+// 2. exact hosts
+// 3. wildcard hosts (`*.preview.local`), the one with the most labels wins
+// 4. the default host, if any
+pub struct Tenants {
+    exact: HashMap<String, SwappableAppRouter>,
+    // (suffix including the leading dot, router), most specific first
+    wildcards: Vec<(String, SwappableAppRouter)>,
+    aliases: HashMap<String, String>,
+    default_host: Option<String>,
+    // the other deployments serving a share of a host (as registered, e.g. `*.a.com`)
+    splits: HashMap<String, Arc<Split>>,
+}
+
+// the router serving a request and the labels matched by the `*` of a wildcard host
+pub struct Tenant<'a> {
+    pub router: &'a SwappableAppRouter,
+    pub subdomain: Option<String>,
+    // the router serves the primary deployment of the split
+    pub split: Option<&'a Split>,
+}
+
+impl Tenants {
+    pub fn try_new(
+        routers: impl IntoIterator<Item = (String, SwappableAppRouter)>,
+        aliases: HashMap<String, String>,
+        default_host: Option<String>,
+    ) -> Result<Self> {
+        let mut exact = HashMap::new();
+        let mut wildcards = vec![];
+        for (host, router) in routers {
+            let host = normalize(&host);
+            let duplicated = match wildcard_suffix(&host)? {
+                Some(suffix) => {
+                    let duplicated = wildcards.iter().any(|(s, _)| *s == suffix);
+                    wildcards.push((suffix.to_string(), router));
+                    duplicated
+                }
+                None => exact.insert(host.clone(), router).is_some(),
+            };
+            if duplicated {
+                bail!("host {} is registered twice", host);
+            }
+        }
+        // more labels first, then alphabetically so the order doesn't depend on the input
+        wildcards.sort_by(|(a, _), (b, _)| labels(b).cmp(&labels(a)).then(a.cmp(b)));
+
+        let aliases: HashMap<String, String> = aliases
+            .into_iter()
+            .map(|(alias, host)| (normalize(&alias), normalize(&host)))
+            .collect();
+        let tenants = Self {
+            exact,
+            wildcards,
+            aliases: HashMap::new(),
+            default_host: default_host.map(|h| normalize(&h)),
+            splits: HashMap::new(),
+        };
+        for (alias, host) in &aliases {
+            if tenants.find(host).is_none() {
+                bail!("alias {} points to unknown host {}", alias, host);
+            }
+        }
+        if let Some(host) = &tenants.default_host {
+            if tenants.find(host).is_none() {
+                bail!("default host {} is unknown", host);
+            }
+        }
+        Ok(Self { aliases, ..tenants })
+    }
+
+    // split the traffic of registered hosts with other deployments
+    pub fn with_splits(
+        mut self,
+        splits: impl IntoIterator<Item = (String, Arc<Split>)>,
+    ) -> Result<Self> {
+        for (host, split) in splits {
+            let host = normalize(&host);
+            let registered = match wildcard_suffix(&host)? {
+                Some(suffix) => self.wildcards.iter().any(|(s, _)| s == suffix),
+                None => self.exact.contains_key(&host),
+            };
+            if !registered {
+                bail!("split of unknown host {}", host);
+            }
+            self.splits.insert(host, split);
+        }
+        Ok(self)
+    }
+
+    // `host` may carry a port
+    pub fn resolve(&self, host: &str) -> Option<Tenant<'_>> {
+        let host = normalize(strip_port(host));
+        let host = self.aliases.get(&host).unwrap_or(&host);
+        self.find(host)
+            .or_else(|| self.default_host.as_ref().and_then(|h| self.find(h)))
+    }
+
+    // every router once with one of its hosts, a router may serve several hosts
+    pub fn routers(&self) -> Vec<(String, &SwappableAppRouter)> {
+        let hosts = self
+            .exact
+            .iter()
+            .map(|(host, r)| (host.clone(), r))
+            .chain(self.wildcards.iter().map(|(s, r)| (format!("*{}", s), r)))
+            .chain(self.splits.iter().flat_map(|(host, split)| {
+                split
+                    .canaries()
+                    .map(move |(deployment, r, _)| (format!("{} ({})", host, deployment), r))
+            }));
+        let mut routers: Vec<(String, &SwappableAppRouter)> = vec![];
+        for (host, router) in hosts {
+            if !routers
+                .iter()
+                .any(|(_, r)| Arc::ptr_eq(&r.routers, &router.routers))
+            {
+                routers.push((host, router));
+            }
+        }
+        routers
+    }
+
+    fn find(&self, host: &str) -> Option<Tenant<'_>> {
+        if let Some(router) = self.exact.get(host) {
+            return Some(Tenant {
+                router,
+                subdomain: None,
+                split: self.splits.get(host).map(|s| s.as_ref()),
+            });
+        }
+        self.wildcards.iter().find_map(|(suffix, router)| {
+            let subdomain = match_wildcard(suffix, host)?;
+            let split = self.splits.get(&format!("*{}", suffix));
+            Some(Tenant {
+                router,
+                subdomain: Some(subdomain.to_string()),
+                split: split.map(|s| s.as_ref()),
+            })
+        })
+    }
+}
+
+// `*.a.com` matches `x.a.com` and `x.y.a.com` but not `a.com`, the rest is the subdomain
+pub(crate) fn match_wildcard<'h>(suffix: &str, host: &'h str) -> Option<&'h str> {
+    let subdomain = host.strip_suffix(suffix)?;
+    (!subdomain.is_empty()).then_some(subdomain)
+}
+
+// `.a.com` for `*.a.com`, None for a plain host
+pub(crate) fn wildcard_suffix(host: &str) -> Result<Option<&str>> {
+    match host.strip_prefix('*') {
+        Some(suffix) if suffix.starts_with('.') && !suffix.contains('*') && suffix.len() > 1 => {
+            Ok(Some(suffix))
+        }
+        Some(_) => bail!("invalid wildcard host {}, expect *.<domain>", host),
+        None if host.contains('*') => bail!("invalid wildcard host {}, expect *.<domain>", host),
+        None => Ok(None),
+    }
+}
+
+fn labels(suffix: &str) -> usize {
+    suffix.matches('.').count()
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn strip_port(host: &str) -> &str {
+    // [::1]:8080
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ProjectConfig;
+
+    fn router(name: &str) -> SwappableAppRouter {
+        let config: ProjectConfig =
+            serde_yaml::from_str(&format!("{{ name: {}, routes: {{}} }}", name)).unwrap();
+        SwappableAppRouter::try_new(name, config).unwrap()
+    }
+
+    fn resolve(tenants: &Tenants, host: &str) -> Option<(String, Option<String>)> {
+        let tenant = tenants.resolve(host)?;
+        Some((tenant.router.load().code.clone(), tenant.subdomain))
+    }
+
+    #[test]
+    fn tenants_should_resolve_with_precedence() -> Result<()> {
+        let tenants = Tenants::try_new(
+            [
+                ("*.local".to_string(), router("local")),
+                ("app.preview.local".to_string(), router("app")),
+                ("*.preview.local".to_string(), router("preview")),
+            ],
+            HashMap::from([("www.app.com".to_string(), "app.preview.local".to_string())]),
+            Some("x.local".to_string()),
+        )?;
+        let hit = |code: &str, sub: Option<&str>| Some((code.to_string(), sub.map(String::from)));
+
+        assert_eq!(
+            resolve(&tenants, "App.Preview.Local:8080"),
+            hit("app", None)
+        );
+        assert_eq!(
+            resolve(&tenants, "feat-1.preview.local"),
+            hit("preview", Some("feat-1"))
+        );
+        assert_eq!(resolve(&tenants, "a.b.local"), hit("local", Some("a.b")));
+        assert_eq!(resolve(&tenants, "www.app.com"), hit("app", None));
+        // unknown hosts go to the default one
+        assert_eq!(resolve(&tenants, "[::1]:3000"), hit("local", Some("x")));
+        assert_eq!(resolve(&tenants, "local"), hit("local", Some("x")));
+        Ok(())
+    }
+
+    #[test]
+    fn tenants_should_carry_splits() -> Result<()> {
+        let split = Arc::new(Split::try_new(
+            "blue",
+            [("green".to_string(), router("green"), Default::default())],
+        )?);
+        let blue = router("blue");
+        let tenants = Tenants::try_new(
+            [
+                ("a.com".to_string(), blue.clone()),
+                ("*.a.com".to_string(), blue),
+                ("b.com".to_string(), router("b")),
+            ],
+            HashMap::new(),
+            None,
+        )?
+        .with_splits([("*.a.com".to_string(), split)])?;
+        let primary = |host: &str| {
+            tenants
+                .resolve(host)?
+                .split
+                .map(|s| s.primary().to_string())
+        };
+        assert_eq!(primary("x.a.com"), Some("blue".to_string()));
+        assert_eq!(primary("a.com"), None);
+        // the canaries are served as well, e.g. their unload listeners run on shutdown
+        assert_eq!(tenants.routers().len(), 3);
+
+        let split = Arc::new(Split::try_new("blue", [])?);
+        let tenants = Tenants::try_new([], HashMap::new(), None)?;
+        assert!(tenants.with_splits([("c.com".to_string(), split)]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tenants_should_reject_invalid_hosts() {
+        let err = |hosts: &[&str], aliases: &[(&str, &str)], default: Option<&str>| {
+            let routers = hosts.iter().map(|h| (h.to_string(), router("a")));
+            let aliases = aliases
+                .iter()
+                .map(|(a, h)| (a.to_string(), h.to_string()))
+                .collect();
+            Tenants::try_new(routers, aliases, default.map(String::from))
+                .err()
+                .map(|e| e.to_string())
+        };
+        assert!(
+            err(&["a.com", "A.com"], &[], None)
+                .unwrap()
+                .contains("twice")
+        );
+        assert!(
+            err(&["a.*.com"], &[], None)
+                .unwrap()
+                .contains("invalid wildcard")
+        );
+        assert!(err(&["*"], &[], None).unwrap().contains("invalid wildcard"));
+        assert!(
+            err(&["a.com"], &[("b.com", "c.com")], None)
+                .unwrap()
+                .contains("unknown host")
+        );
+        assert!(
+            err(&["a.com"], &[], Some("c.com"))
+                .unwrap()
+                .contains("unknown")
+        );
+        assert!(err(&["*.a.com"], &[("b.com", "x.a.com")], None).is_none());
+    }
+}
@@ -0,0 +1,201 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result, anyhow, bail};
+use arc_swap::ArcSwap;
+use indexmap::IndexMap;
+use rustls::{
+    ServerConfig,
+    crypto::ring,
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::tenant::{match_wildcard, wildcard_suffix};
+
+// how often the certificate files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// pem encoded certificate chain and private key of a host
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Debug)]
+struct HostCert {
+    files: TlsFiles,
+    // modification time of the files the current key was loaded from
+    modified: Mutex<Option<SystemTime>>,
+    key: ArcSwap<CertifiedKey>,
+}
+
+// pick the certificate by the SNI of the client hello, the first host is the default
+#[derive(Debug)]
+pub struct CertResolver {
+    certs: IndexMap<String, HostCert>,
+}
+
+impl CertResolver {
+    // None if no host has a certificate
+    pub fn try_new<'a>(
+        hosts: impl IntoIterator<Item = (&'a str, &'a TlsFiles)>,
+    ) -> Result<Option<Self>> {
+        let mut certs = IndexMap::new();
+        for (host, files) in hosts {
+            let key =
+                load_key(files).with_context(|| format!("invalid certificate of {}", host))?;
+            let cert = HostCert {
+                files: files.clone(),
+                modified: Mutex::new(modified(files)),
+                key: ArcSwap::from_pointee(key),
+            };
+            certs.insert(host.to_string(), cert);
+        }
+        Ok((!certs.is_empty()).then_some(Self { certs }))
+    }
+
+    pub fn server_config(self: Arc<Self>) -> Result<Arc<ServerConfig>> {
+        let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self);
+        // prefer http/2, the connection is served by whatever protocol was negotiated
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(Arc::new(config))
+    }
+
+    // exact host, then the most specific wildcard host, then the default
+    fn cert_for(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let name = server_name.map(|n| n.to_ascii_lowercase());
+        let cert = name
+            .as_deref()
+            .and_then(|name| {
+                self.certs.get(name).or_else(|| {
+                    self.certs
+                        .iter()
+                        .filter_map(|(host, cert)| {
+                            let suffix = wildcard_suffix(host).ok()??;
+                            match_wildcard(suffix, name).map(|_| (suffix.len(), cert))
+                        })
+                        .max_by_key(|(len, _)| *len)
+                        .map(|(_, cert)| cert)
+                })
+            })
+            .or_else(|| self.certs.first().map(|(_, cert)| cert))?;
+        Some(cert.key.load_full())
+    }
+
+    // reload the certificates whose files changed, a broken file keeps the old certificate
+    pub fn reload(&self) {
+        for (host, cert) in &self.certs {
+            let modified = modified(&cert.files);
+            if *cert.modified.lock().unwrap() == modified {
+                continue;
+            }
+            match load_key(&cert.files) {
+                Ok(key) => {
+                    cert.key.store(Arc::new(key));
+                    *cert.modified.lock().unwrap() = modified;
+                    info!("certificate of {} reloaded", host);
+                }
+                Err(e) => warn!("failed to reload the certificate of {}: {:?}", host, e),
+            }
+        }
+    }
+
+    pub async fn watch(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.reload();
+        }
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.cert_for(client_hello.server_name())
+    }
+}
+
+fn load_key(files: &TlsFiles) -> Result<CertifiedKey> {
+    let mut reader = BufReader::new(File::open(&files.cert)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        bail!("no certificate found in {}", files.cert.display());
+    }
+    let mut reader = BufReader::new(File::open(&files.key)?);
+    let key = rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow!("no private key found in {}", files.key.display()))?;
+    let key = ring::sign::any_supported_type(&key)?;
+    Ok(CertifiedKey::new(certs, key))
+}
+
+// the latest modification time of the cert and key files
+fn modified(files: &TlsFiles) -> Option<SystemTime> {
+    let cert = files.cert.metadata().and_then(|m| m.modified()).ok()?;
+    let key = files.key.metadata().and_then(|m| m.modified()).ok()?;
+    Some(cert.max(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn write_cert(dir: &std::path::Path, host: &str) -> TlsFiles {
+        let cert = rcgen::generate_simple_self_signed(vec![host.to_string()]).unwrap();
+        let files = TlsFiles {
+            cert: dir.join(format!("{}.crt", host)),
+            key: dir.join(format!("{}.key", host)),
+        };
+        fs::write(&files.cert, cert.cert.pem()).unwrap();
+        fs::write(&files.key, cert.key_pair.serialize_pem()).unwrap();
+        files
+    }
+
+    #[test]
+    fn cert_should_be_chosen_by_sni_and_reloaded() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("dino-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let a = write_cert(&dir, "a.com");
+        let b = write_cert(&dir, "b.com");
+        let wildcard = write_cert(&dir, "x.b.com");
+        let resolver =
+            CertResolver::try_new([("a.com", &a), ("b.com", &b), ("*.b.com", &wildcard)])?.unwrap();
+
+        let cert_a = resolver.cert_for(Some("a.com")).unwrap();
+        let cert_b = resolver.cert_for(Some("b.com")).unwrap();
+        assert_ne!(cert_a.cert, cert_b.cert);
+        // unknown or missing SNI falls back to the first host
+        assert_eq!(resolver.cert_for(None).unwrap().cert, cert_a.cert);
+        assert_eq!(resolver.cert_for(Some("c.com")).unwrap().cert, cert_a.cert);
+        let cert_wildcard = resolver.cert_for(Some("x.b.com")).unwrap();
+        assert_ne!(cert_wildcard.cert, cert_b.cert);
+        assert_ne!(cert_wildcard.cert, cert_a.cert);
+
+        // a broken file keeps the old certificate
+        fs::write(&b.cert, "broken")?;
+        *resolver.certs["b.com"].modified.lock().unwrap() = None;
+        resolver.reload();
+        assert_eq!(resolver.cert_for(Some("b.com")).unwrap().cert, cert_b.cert);
+
+        write_cert(&dir, "b.com");
+        *resolver.certs["b.com"].modified.lock().unwrap() = None;
+        resolver.reload();
+        assert_ne!(resolver.cert_for(Some("b.com")).unwrap().cert, cert_b.cert);
+
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
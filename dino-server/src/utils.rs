@@ -0,0 +1,71 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+};
+
+// match a value against a pattern where `*` matches any (possibly empty) sequence of chars
+pub(crate) fn wildcard_match(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    // split always yields at least one item
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // no `*` in the pattern, need an exact match
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+// a single dns label, so `<project>-<hash>.<domain>` is a valid host
+pub(crate) fn is_valid_label(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+// good enough to split the traffic or tag the requests, not for secrets
+pub(crate) fn random_u64() -> u64 {
+    // every RandomState is seeded differently
+    RandomState::new().build_hasher().finish()
+}
+
+// compare secrets without leaking where they differ through timing
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_match_should_work() {
+        assert!(wildcard_match("*", "https://a.com"));
+        assert!(wildcard_match("https://a.com", "https://a.com"));
+        assert!(!wildcard_match("https://a.com", "https://a.com.cn"));
+        assert!(wildcard_match("https://*.a.com", "https://x.y.a.com"));
+        assert!(!wildcard_match("https://*.a.com", "https://a.com"));
+        assert!(!wildcard_match("https://*.a.com", "http://x.a.com"));
+        assert!(wildcard_match(
+            "http://localhost:*",
+            "http://localhost:3000"
+        ));
+        assert!(wildcard_match("*.a.*", "x.a.com"));
+    }
+}
@@ -10,8 +10,8 @@ pub struct BuildOpts {}
 impl CmdExecutor for BuildOpts {
     async fn execute(self) -> anyhow::Result<()> {
         let cur_dir = env::current_dir()?.display().to_string();
-        let filename = build_project(&cur_dir)?;
-        eprintln!("Build success, output: {}", filename);
+        let artifact = build_project(&cur_dir)?;
+        eprintln!("Build success, output: {}", artifact.dir().display());
         Ok(())
     }
 }
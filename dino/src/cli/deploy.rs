@@ -0,0 +1,88 @@
+use std::env;
+
+use anyhow::{Context, bail};
+use clap::Parser;
+use dino_server::{DeployRequest, ProjectInfo};
+
+use crate::{CmdExecutor, utils::build_project};
+
+#[derive(Debug, Parser)]
+pub struct DeployOpts {
+    // url of the admin api of the dino-server, e.g. http://127.0.0.1:9999 or https
+    #[clap(short, long)]
+    pub server: String,
+    // defaults to the name in config.yml
+    #[clap(short, long)]
+    pub project: Option<String>,
+    // production hosts, required by the first production deployment, can be repeated
+    #[clap(long = "host")]
+    pub hosts: Vec<String>,
+    // bearer token of the admin api
+    #[clap(long, env = "DINO_ADMIN_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+    // serve the deployment on the production hosts instead of its preview url only
+    #[clap(long)]
+    pub prod: bool,
+}
+
+impl CmdExecutor for DeployOpts {
+    async fn execute(self) -> anyhow::Result<()> {
+        if !["http://", "https://"]
+            .iter()
+            .any(|s| self.server.starts_with(s))
+        {
+            bail!(
+                "invalid server {}, expect an http:// or https:// url",
+                self.server
+            );
+        }
+        let cur_dir = env::current_dir()?.display().to_string();
+        let artifact = build_project(&cur_dir)?;
+        let project = match self.project {
+            Some(project) => project,
+            None => artifact.config()?.name,
+        };
+
+        let hash = artifact.manifest.hash.clone();
+        let url = format!("{}/projects/{}", self.server.trim_end_matches('/'), project);
+        let body = DeployRequest {
+            hosts: self.hosts,
+            code: artifact.code()?,
+            config: artifact.raw_config()?,
+            files: artifact.files()?,
+            prod: self.prod,
+            manifest: Some(artifact.manifest),
+        };
+        let token = self.token;
+        // ureq is blocking
+        let info = tokio::task::spawn_blocking(move || {
+            let mut req = ureq::put(&url).config().http_status_as_error(false).build();
+            if let Some(token) = token {
+                req = req.header("authorization", &format!("Bearer {}", token));
+            }
+            let mut res = req
+                .send_json(&body)
+                .with_context(|| format!("failed to reach {}", url))?;
+            let status = res.status();
+            if !status.is_success() {
+                let reason = res.body_mut().read_to_string().unwrap_or_default();
+                bail!("deploy failed ({}): {}", status, reason);
+            }
+            Ok(res.body_mut().read_json::<ProjectInfo>()?)
+        })
+        .await??;
+
+        // the last one may be another build deployed meanwhile, or an older one redeployed
+        let Some(deployment) = info.deployments.iter().find(|d| d.hash == hash) else {
+            bail!("the server didn't record the deployment");
+        };
+        eprintln!("Deployed {} ({})", info.name, deployment.hash);
+        if let Some(url) = &deployment.preview_url {
+            eprintln!("Preview: {}", url);
+        }
+        if info.active.as_ref() == Some(&deployment.hash) {
+            eprintln!("Production: {}", info.hosts.join(", "));
+        }
+        Ok(())
+    }
+}
@@ -2,11 +2,15 @@ use clap::Parser;
 use enum_dispatch::enum_dispatch;
 
 pub use build::BuildOpts;
+pub use deploy::DeployOpts;
 pub use init::InitOpts;
+pub use routes::RoutesOpts;
 pub use run::RunOpts;
 
 mod build;
+mod deploy;
 mod init;
+mod routes;
 mod run;
 
 #[derive(Debug, Parser)]
@@ -25,4 +29,8 @@ pub enum SubCommand {
     Build(BuildOpts),
     #[command(name = "run", about = "Run deno project")]
     Run(RunOpts),
+    #[command(name = "routes", about = "Show the routes of deno project")]
+    Routes(RoutesOpts),
+    #[command(name = "deploy", about = "Deploy deno project to a dino-server")]
+    Deploy(DeployOpts),
 }
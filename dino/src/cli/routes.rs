@@ -0,0 +1,66 @@
+use std::collections::HashSet;
+
+use clap::Parser;
+use dino_server::{JsWorker, RouteAction, SwappableAppRouter};
+
+use crate::{CmdExecutor, utils::get_code_and_config};
+
+#[derive(Debug, Parser)]
+pub struct RoutesOpts {}
+
+impl CmdExecutor for RoutesOpts {
+    async fn execute(self) -> anyhow::Result<()> {
+        let (config, code) = get_code_and_config(".")?;
+        // surface conflicts and invalid routes the same way `dino run` would
+        SwappableAppRouter::try_new(&code, config.clone())?;
+
+        let exported: HashSet<String> = JsWorker::try_new(&code)?.handlers()?.into_iter().collect();
+
+        let mut rows = vec![[
+            "METHOD".to_string(),
+            "PATH".to_string(),
+            "TARGET".to_string(),
+            String::new(),
+        ]];
+        let mut missing = 0;
+        for (path, routes) in &config.routes {
+            for route in routes {
+                let note = match &route.action {
+                    RouteAction::Handler(name) if !exported.contains(name) => {
+                        missing += 1;
+                        "(not exported)"
+                    }
+                    _ => "",
+                };
+                rows.push([
+                    route.method.to_string(),
+                    path.clone(),
+                    route.action.to_string(),
+                    note.to_string(),
+                ]);
+            }
+        }
+
+        let widths: Vec<usize> = (0..3)
+            .map(|i| rows.iter().map(|row| row[i].len()).max().unwrap_or(0))
+            .collect();
+        for [method, path, target, note] in rows {
+            let line = format!(
+                "{:w0$}  {:w1$}  {:w2$}  {}",
+                method,
+                path,
+                target,
+                note,
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2]
+            );
+            println!("{}", line.trim_end());
+        }
+
+        if missing > 0 {
+            eprintln!("{} handler(s) not exported by the bundle", missing);
+        }
+        Ok(())
+    }
+}
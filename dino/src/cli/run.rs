@@ -1,7 +1,17 @@
-use std::{fs, path::Path, time::Duration};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::Context;
 use clap::Parser;
-use dino_server::{ProjectConfig, SwappableAppRouter, TenentRouter, start_server};
+use dino_server::{
+    ARTIFACT_MANIFEST, AccessLogFormat, Artifact, ListenAddr, ManifestProject, ServerConfig,
+    ServerManifest, SocketMode, Split, SwappableAppRouter, TelemetryConfig, TenentRouter, TlsFiles,
+    start_server,
+};
 use notify_debouncer_mini::new_debouncer;
 use tokio_stream::{StreamExt, wrappers::ReceiverStream};
 use tracing::{info, level_filters::LevelFilter};
@@ -9,14 +19,45 @@ use tracing_subscriber::{
     Layer as _, fmt::Layer, layer::SubscriberExt as _, util::SubscriberInitExt as _,
 };
 
-use crate::{CmdExecutor, utils::build_project};
+use crate::{
+    BUILD_DIR, CmdExecutor,
+    utils::{PUBLIC_DIR, get_code_and_config, get_dev_cert},
+};
 
 const MONITOR_FS_INTERVAL: Duration = Duration::from_secs(2);
+const MANIFEST: &str = "dino-server.yml";
 
 #[derive(Debug, Parser)]
 pub struct RunOpts {
+    // shorthand for `--listen 127.0.0.1:<port>`
+    #[clap(short, long)]
+    pub port: Option<u16>,
+    // e.g. 127.0.0.1:3000, [::1]:3000 or unix:/tmp/dino.sock, can be repeated
     #[clap(short, long)]
-    pub port: u16,
+    pub listen: Vec<ListenAddr>,
+    // permissions of the unix sockets in octal, e.g. 660
+    #[clap(long)]
+    pub socket_mode: Option<SocketMode>,
+    // keep the `caches` of the handlers in .build/ across restarts
+    #[clap(long)]
+    pub persist_caches: bool,
+    // serve https with a self-signed certificate for the hosts without one
+    #[clap(long)]
+    pub https: bool,
+    // seconds to wait for the in-flight requests on shutdown
+    #[clap(long)]
+    pub shutdown_timeout: Option<u64>,
+    // log the requests as common, combined or json, to stdout unless the manifest says otherwise
+    #[clap(long)]
+    pub access_log: Option<AccessLogFormat>,
+    // export the spans of the requests to the otlp/http collector, e.g.
+    // http://localhost:4318/v1/traces
+    #[clap(long)]
+    pub otlp_endpoint: Option<String>,
+    // projects to serve, defaults to dino-server.yml if it exists, otherwise the current
+    // directory is served on localhost
+    #[clap(short, long)]
+    pub manifest: Option<PathBuf>,
 }
 
 impl CmdExecutor for RunOpts {
@@ -24,36 +65,149 @@ impl CmdExecutor for RunOpts {
         let layer = Layer::new().with_filter(LevelFilter::INFO);
         tracing_subscriber::registry().with(layer).init();
 
-        let (config, code) = get_code_and_config()?;
-
-        let router = SwappableAppRouter::try_new(&code, config.routes)?;
-        let routers = vec![TenentRouter::new("localhost", router.clone())];
+        let manifest = self.load_manifest()?;
+        let hosts: Vec<String> = manifest
+            .projects
+            .iter()
+            .flat_map(|p| p.hosts.clone())
+            .collect();
+        let dev_cert = if self.https {
+            let (cert, key) = get_dev_cert(&hosts)?;
+            info!("Using the self-signed certificate {}", cert.display());
+            Some(TlsFiles { cert, key })
+        } else {
+            None
+        };
 
-        tokio::spawn(async_watch(Path::new("."), router));
+        let mut routers = vec![];
+        for project in manifest.projects {
+            let router = self.serve_dir(&project.dir)?;
+            info!(
+                "Serving {} on {}",
+                project.dir.display(),
+                project.hosts.join(", ")
+            );
+            // the deployments are named after their dirs, e.g. `blue` and `green`
+            let split = match project.split.is_empty() {
+                true => None,
+                false => {
+                    let mut canaries = vec![];
+                    for target in project.split {
+                        let router = self.serve_dir(&target.dir)?;
+                        let name = deployment_name(&target.dir)?;
+                        info!("Serving {} to {}% of the traffic", name, target.rule.weight);
+                        canaries.push((name, router, target.rule));
+                    }
+                    let primary = deployment_name(&project.dir)?;
+                    Some(Arc::new(Split::try_new(primary, canaries)?))
+                }
+            };
+            let tls = project.tls.or_else(|| dev_cert.clone());
+            for host in project.hosts {
+                let mut tenant = TenentRouter::new(host, router.clone());
+                if let Some(tls) = &tls {
+                    tenant = tenant.with_tls(&tls.cert, &tls.key);
+                }
+                if let Some(split) = &split {
+                    tenant = tenant.with_split(split.clone());
+                }
+                routers.push(tenant);
+            }
+        }
 
-        start_server(self.port, routers).await?;
+        let mut config = manifest.server;
+        config.listen.extend(self.listen);
+        if let Some(port) = self.port {
+            config.listen.push(ListenAddr::Tcp(SocketAddr::from((
+                Ipv4Addr::LOCALHOST,
+                port,
+            ))));
+        }
+        if let Some(mode) = self.socket_mode {
+            config.socket_mode = Some(mode);
+        }
+        if let Some(timeout) = self.shutdown_timeout {
+            config.shutdown_timeout = Duration::from_secs(timeout);
+        }
+        if let Some(format) = self.access_log {
+            config
+                .access_log
+                .get_or_insert_with(Default::default)
+                .format = format;
+        }
+        if let Some(endpoint) = self.otlp_endpoint {
+            match &mut config.telemetry {
+                Some(telemetry) => telemetry.endpoint = endpoint,
+                None => config.telemetry = Some(TelemetryConfig::new(endpoint)),
+            }
+        }
+        start_server(config, routers).await?;
         Ok(())
     }
 }
 
-fn get_code_and_config() -> Result<(ProjectConfig, String), anyhow::Error> {
-    let filename = build_project(".")?;
-    let config = filename.replace(".mjs", ".yml");
-    let config = ProjectConfig::load(config)?;
-    let code = fs::read_to_string(filename)?;
-    Ok((config, code))
+impl RunOpts {
+    // the router of a project dir, rebuilt on changes unless it's a prebuilt artifact
+    fn serve_dir(&self, dir: &Path) -> anyhow::Result<SwappableAppRouter> {
+        // a prebuilt artifact, e.g. from `dino build`, is served as is
+        let prebuilt = dir.join(ARTIFACT_MANIFEST).exists();
+        let name = dir.display().to_string();
+        let (config, code) = if prebuilt {
+            let artifact = Artifact::load(dir)?;
+            (artifact.config()?, artifact.code()?)
+        } else {
+            get_code_and_config(&name)?
+        };
+        let router = SwappableAppRouter::try_new(&code, config)?;
+        if self.persist_caches {
+            router.persist_caches(dir.join(BUILD_DIR).join("caches.json"))?;
+        }
+        if !prebuilt {
+            tokio::spawn(async_watch(name, router.clone()));
+        }
+        Ok(router)
+    }
+
+    fn load_manifest(&self) -> anyhow::Result<ServerManifest> {
+        let filename = self
+            .manifest
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(MANIFEST));
+        if self.manifest.is_some() || filename.exists() {
+            return ServerManifest::load(&filename);
+        }
+        Ok(ServerManifest {
+            server: ServerConfig::default(),
+            projects: vec![ManifestProject {
+                dir: PathBuf::from("."),
+                hosts: vec!["localhost".to_string()],
+                tls: None,
+                split: vec![],
+            }],
+        })
+    }
+}
+
+fn deployment_name(dir: &Path) -> anyhow::Result<String> {
+    let dir = dir
+        .canonicalize()
+        .with_context(|| format!("failed to find {}", dir.display()))?;
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    Ok(name.to_string())
 }
 
-async fn async_watch(p: impl AsRef<Path>, router: SwappableAppRouter) -> Result<(), anyhow::Error> {
+// rebuild the project in the dir and swap its router when the code or config changes
+async fn async_watch(dir: String, router: SwappableAppRouter) -> Result<(), anyhow::Error> {
     let (tx, rx) = tokio::sync::mpsc::channel(1);
 
     let mut debouncer = new_debouncer(MONITOR_FS_INTERVAL, move |res| {
-        tx.blocking_send(res).unwrap();
+        // fails once the runtime is shutting down, nothing to do then
+        let _ = tx.blocking_send(res);
     })?;
 
     debouncer
         .watcher()
-        .watch(p.as_ref(), notify::RecursiveMode::Recursive)?;
+        .watch(Path::new(&dir), notify::RecursiveMode::Recursive)?;
 
     let mut stream = ReceiverStream::new(rx);
     while let Some(ret) = stream.next().await {
@@ -62,7 +216,12 @@ async fn async_watch(p: impl AsRef<Path>, router: SwappableAppRouter) -> Result<
                 let mut need_swap = false;
                 for event in events {
                     let ext = event.path.extension().unwrap_or_default();
-                    if event.path.ends_with("config.yml") || ext == "ts" {
+                    // the artifacts carry a config.yml too
+                    let in_dir = |name| event.path.components().any(|c| c.as_os_str() == name);
+                    if in_dir(BUILD_DIR) {
+                        continue;
+                    }
+                    if event.path.ends_with("config.yml") || ext == "ts" || in_dir(PUBLIC_DIR) {
                         match event.kind {
                             notify_debouncer_mini::DebouncedEventKind::Any => {
                                 info!("File changed (stable): {:?}", event.path.display());
@@ -82,8 +241,14 @@ async fn async_watch(p: impl AsRef<Path>, router: SwappableAppRouter) -> Result<
                     }
                 }
                 if need_swap {
-                    let (config, code) = get_code_and_config()?;
-                    router.swap(code, config.routes)?;
+                    // keep serving the old code if the new one fails to build
+                    let ret = router.reload(|| get_code_and_config(&dir));
+                    match ret {
+                        Ok(()) => info!("Router of {} swapped", dir),
+                        Err(e) => {
+                            tracing::error!("hot reload failed, keep the old router: {:?}", e)
+                        }
+                    }
                 }
             }
             Err(e) => {
@@ -1,34 +1,45 @@
 use anyhow::Result;
 
-use bundle::run_bundle;
+use bundle::run_bundle_with_source_map;
+use dino_server::{Artifact, ArtifactSource, BuildMetadata, ProjectConfig};
 use glob::{GlobError, glob};
 use std::{
     collections::BTreeSet,
     fs::{self, File},
-    io,
+    io::Write,
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use tracing::warn;
+
 use crate::BUILD_DIR;
 
+const ENTRY: &str = "main.ts";
+// static files of the project, shipped with its artifact
+pub(crate) const PUBLIC_DIR: &str = "public";
+
 // get all files with certain extension in a directory
 pub(crate) fn get_files_with_exts(dir: &str, exts: &[&str]) -> Result<BTreeSet<PathBuf>> {
-    // glob all ts files
+    // glob all ts files, the build output (e.g. persisted caches) is not part of the project
+    let build_dir = Path::new(dir).join(BUILD_DIR);
     let mut files = BTreeSet::new();
     for ext in exts {
         let rule = format!("{}/**/*.{}", dir, ext);
         let paths = glob(&rule)?.collect::<Result<BTreeSet<PathBuf>, GlobError>>()?;
-        files.extend(paths);
+        files.extend(paths.into_iter().filter(|p| !p.starts_with(&build_dir)));
     }
     Ok(files)
 }
 
+// covers config.yml and the public files as well, a build is deployed as a whole
 pub(crate) fn calc_project_hash(dir: &str) -> Result<String> {
-    calc_hash_for_files(dir, &["ts", "js", "json"], 16)
+    let mut files = get_files_with_exts(dir, &["ts", "js", "json", "yml"])?;
+    files.extend(get_public_files(dir)?);
+    hash_files(files, 16)
 }
 
-pub(crate) fn calc_hash_for_files(dir: &str, exts: &[&str], len: usize) -> Result<String> {
-    let files = get_files_with_exts(dir, exts)?;
+fn hash_files(files: BTreeSet<PathBuf>, len: usize) -> Result<String> {
     let mut hasher = blake3::Hasher::new();
     for file in files {
         hasher.update_reader(File::open(file)?)?;
@@ -38,23 +49,101 @@ pub(crate) fn calc_hash_for_files(dir: &str, exts: &[&str], len: usize) -> Resul
     Ok(ret)
 }
 
-pub(crate) fn build_project(dir: &str) -> Result<String> {
+// the static files shipped as the assets of the artifact
+pub(crate) fn get_public_files(dir: &str) -> Result<BTreeSet<PathBuf>> {
+    let rule = format!("{}/{}/**/*", dir, PUBLIC_DIR);
+    let paths = glob(&rule)?.collect::<Result<BTreeSet<PathBuf>, GlobError>>()?;
+    Ok(paths.into_iter().filter(|p| p.is_file()).collect())
+}
+
+// the output goes to the build dir of the project, an artifact per project hash
+pub(crate) fn build_project(dir: &str) -> Result<Artifact> {
     let hash = calc_project_hash(dir)?;
-    fs::create_dir_all(BUILD_DIR)?;
-    let filename = format!("{}/{}.mjs", BUILD_DIR, hash);
-    let config_filename = format!("{}/{}.yml", BUILD_DIR, hash);
-    let dst = Path::new(&filename);
-    // if the file already exists, skip building
-    if dst.exists() {
-        return Ok(filename);
+    let root = Path::new(dir);
+    let artifact_dir = root.join(BUILD_DIR).join(&hash);
+    // the same sources were built already, unless the artifact got broken
+    if artifact_dir.exists() {
+        match Artifact::load(&artifact_dir) {
+            Ok(artifact) => return Ok(artifact),
+            Err(e) => warn!("rebuilding {}: {:#}", artifact_dir.display(), e),
+        }
+    }
+
+    let entry = root.join(ENTRY).display().to_string();
+    let (bundle, source_map) = run_bundle_with_source_map(&entry, &Default::default())?;
+    let public = root.join(PUBLIC_DIR);
+    let mut assets = vec![];
+    for file in get_public_files(dir)? {
+        let path = file
+            .strip_prefix(&public)?
+            .to_string_lossy()
+            .replace('\\', "/");
+        assets.push((path, file));
+    }
+    let metadata = BuildMetadata {
+        dino_version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        entry: ENTRY.to_string(),
+    };
+    let filename = root.join("config.yml");
+    let config = fs::read_to_string(&filename)?;
+    // the files the config refers to are shipped with it, it's deployed without the project
+    let files = ProjectConfig::load(&filename)?
+        .files()
+        .into_iter()
+        .map(|path| (path.to_string(), root.join(path)))
+        .collect();
+    let source = ArtifactSource {
+        bundle,
+        source_map: Some(source_map),
+        config,
+        assets,
+        files,
+    };
+    // refuses to write a bundle that misses configured handlers
+    Artifact::write(artifact_dir, hash, metadata, source)
+}
+
+pub(crate) fn get_code_and_config(dir: &str) -> Result<(ProjectConfig, String)> {
+    let artifact = build_project(dir)?;
+    Ok((artifact.config()?, artifact.code()?))
+}
+
+// self-signed certificate for localhost and the hosts, generated once per set of hosts
+// and cached in the build dir
+pub(crate) fn get_dev_cert(hosts: &[String]) -> Result<(PathBuf, PathBuf)> {
+    let mut names: BTreeSet<String> = ["localhost", "127.0.0.1", "::1"].map(String::from).into();
+    names.extend(hosts.iter().cloned());
+    let names: Vec<String> = names.into_iter().collect();
+    let mut hash = blake3::hash(names.join(",").as_bytes()).to_string();
+    hash.truncate(16);
+
+    let dir = Path::new(BUILD_DIR).join("tls");
+    let cert = dir.join(format!("dev-{}.crt", hash));
+    let key = dir.join(format!("dev-{}.key", hash));
+    if cert.exists() && key.exists() {
+        return Ok((cert, key));
     }
 
-    // build the project
-    let content = run_bundle("main.ts", &Default::default())?;
-    fs::write(dst, content)?;
+    let generated = rcgen::generate_simple_self_signed(names)?;
+    fs::create_dir_all(&dir)?;
+    fs::write(&cert, generated.cert.pem())?;
+    write_private(&key, generated.key_pair.serialize_pem().as_bytes())?;
+    Ok((cert, key))
+}
 
-    let mut dst = File::create(config_filename)?;
-    let mut src = File::open("config.yml")?;
-    io::copy(&mut src, &mut dst)?;
-    Ok(filename)
+// readable by the owner only, e.g. a private key
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)?;
+    Ok(())
 }